@@ -0,0 +1,16 @@
+use std::time::Instant;
+
+use asmintr::Interpreter;
+
+// Time per step of a loop of mov, mul, dec, inc, cmp and conditional jumps, run with `cargo bench`.
+// It only uses the public API, so it runs unchanged against older versions to compare dispatch
+fn main() {
+    let program = "mov   n, 0\nloop:\n    mov   a, 1\n    mov   c, 10\nfact:\n    mul   a, c\n    dec   c\n    cmp   c, 1\n    jg    fact\n    inc   n\n    cmp   n, 500000\n    jl    loop\nmsg 'done ', a\nend\n";
+    for _ in 0..5 {
+        let mut interpreter = Interpreter::new(program);
+        let start = Instant::now();
+        interpreter.run().unwrap();
+        let elapsed = start.elapsed();
+        println!("{:?} {:.2}ns", elapsed, elapsed.as_nanos() as f64 / interpreter.metrics().steps as f64);
+    }
+}
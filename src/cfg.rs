@@ -0,0 +1,304 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+
+use crate::{Instruction, LabelKind, Program, Symbol, Warning};
+
+#[derive(Debug, Default)]
+pub struct BasicBlock {
+    pub labels: Vec<String>,
+    pub instructions: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    FallThrough,
+    Jump,
+    Branch,
+    Call,
+}
+
+impl Display for EdgeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EdgeKind::FallThrough => write!(f, "fallthrough"),
+            EdgeKind::Jump => write!(f, "jump"),
+            EdgeKind::Branch => write!(f, "branch"),
+            EdgeKind::Call => write!(f, "call"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+pub struct Cfg<'p> {
+    program: &'p Program<'p>,
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+}
+
+enum Transfer<'i> {
+    Next,
+    Jump(&'i Symbol),
+    Branch(&'i Symbol),
+    Call(&'i Symbol),
+    Stop,
+}
+
+fn transfer(instruction: &Instruction) -> Transfer<'_> {
+    match instruction {
+        Instruction::Jmp(label) => Transfer::Jump(label),
+        Instruction::Jne(label)
+        | Instruction::Je(label)
+        | Instruction::Jge(label)
+        | Instruction::Jg(label)
+        | Instruction::Jle(label)
+        | Instruction::Jl(label)
+        | Instruction::Jcxz(_, label) => Transfer::Branch(label),
+        Instruction::Call(label) | Instruction::CallExpr(_, label) => Transfer::Call(label),
+        Instruction::Ret(_) | Instruction::End | Instruction::Abort(_) => Transfer::Stop,
+        // Skipped when the condition fails, so execution may always fall through
+        Instruction::Cond(_, inner) => match transfer(inner) {
+            Transfer::Jump(label) => Transfer::Branch(label),
+            Transfer::Stop => Transfer::Next,
+            other => other,
+        },
+        _ => Transfer::Next,
+    }
+}
+
+impl Instruction {
+    // Indices execution may go on at after this instruction at `index`, jump targets first. A `call` also
+    // continues after itself once the callee returns, while `ret` has none since that depends on the stack
+    pub fn successors(&self, index: usize, program: &Program) -> Vec<usize> {
+        let next = program.resolve(index + 1);
+        let target = |label: &Symbol| program.functions.get(label).and_then(|&target| program.resolve(target));
+        match transfer(self) {
+            Transfer::Next => next.into_iter().collect(),
+            Transfer::Jump(label) => target(label).into_iter().collect(),
+            Transfer::Branch(label) | Transfer::Call(label) => target(label).into_iter().chain(next).collect(),
+            Transfer::Stop => vec![],
+        }
+    }
+}
+
+impl<'p> Cfg<'p> {
+    pub fn build(program: &'p Program<'p>) -> Self {
+        let instructions = &program.instructions;
+        let resolve = |index: usize| program.resolve(index);
+
+        // Find leaders
+        let mut leaders = BTreeSet::new();
+        leaders.extend(resolve(0));
+        leaders.extend(program.functions.values().filter_map(|&target| resolve(target)));
+        for (index, instruction) in instructions.iter().enumerate() {
+            if instruction.is_executable() && !matches!(transfer(instruction), Transfer::Next) {
+                leaders.extend(resolve(index + 1));
+            }
+        }
+
+        // Split executable instructions into blocks
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut block_of = HashMap::new();
+        for (index, instruction) in instructions.iter().enumerate() {
+            if !instruction.is_executable() {
+                continue;
+            }
+            if leaders.contains(&index) || blocks.is_empty() {
+                blocks.push(BasicBlock::default());
+            }
+            block_of.insert(index, blocks.len() - 1);
+            blocks.last_mut().unwrap().instructions.push(index);
+        }
+
+        // Attach labels to the blocks they lead into
+        for (index, instruction) in instructions.iter().enumerate() {
+            if let Instruction::Function(name) = instruction {
+                if let Some(target) = resolve(index + 1) {
+                    blocks[block_of[&target]].labels.push(name.to_string());
+                }
+            }
+        }
+
+        // Connect blocks
+        let target_of = |label: &Symbol| {
+            program.functions.get(label)
+                .and_then(|&target| resolve(target))
+                .map(|target| block_of[&target])
+        };
+        let mut edges = Vec::new();
+        for (from, block) in blocks.iter().enumerate() {
+            let last = *block.instructions.last().unwrap();
+            let next = resolve(last + 1).map(|index| block_of[&index]);
+            let mut connect = |to: Option<usize>, kind| {
+                if let Some(to) = to {
+                    edges.push(Edge { from, to, kind });
+                }
+            };
+
+            match transfer(&instructions[last]) {
+                Transfer::Next => connect(next, EdgeKind::FallThrough),
+                Transfer::Jump(label) => connect(target_of(label), EdgeKind::Jump),
+                Transfer::Branch(label) => {
+                    connect(target_of(label), EdgeKind::Branch);
+                    connect(next, EdgeKind::FallThrough);
+                }
+                Transfer::Call(label) => {
+                    connect(target_of(label), EdgeKind::Call);
+                    connect(next, EdgeKind::FallThrough);
+                }
+                Transfer::Stop => {}
+            }
+        }
+
+        Self { program, blocks, edges }
+    }
+
+    // Called functions with a path that runs past their last instruction, into the next function or off the
+    // end of the program, instead of leaving through `ret`, `end` or a jump. Each is reported once
+    pub fn missing_returns(&self) -> Vec<Warning> {
+        let program = self.program;
+        let functions: Vec<String> = program.entry_points().into_iter()
+            .filter(|(_, kind)| *kind == LabelKind::Entry)
+            .map(|(name, _)| name)
+            .collect();
+        let function_at = |block: usize| self.blocks[block].labels.iter().find(|label| functions.contains(label));
+        let in_prelude = |block: usize| program.prelude_start.is_some_and(|start| self.blocks[block].instructions[0] >= start);
+
+        let mut warnings = Vec::new();
+        for function in functions.iter() {
+            let Some(entry) = (0..self.blocks.len()).find(|&block| self.blocks[block].labels.contains(function)) else {
+                continue;
+            };
+            if in_prelude(entry) {
+                continue;
+            }
+
+            // Walk the blocks of the function, calls come back to the block after them
+            let mut seen = BTreeSet::from([entry]);
+            let mut pending = vec![entry];
+            while let Some(block) = pending.pop() {
+                let last = *self.blocks[block].instructions.last().unwrap();
+                let falls_through = !matches!(transfer(&program.instructions[last]), Transfer::Jump(_) | Transfer::Stop);
+                let next = self.edges.iter()
+                    .find(|edge| edge.from == block && edge.kind == EdgeKind::FallThrough)
+                    .map(|edge| edge.to)
+                    .filter(|&next| !in_prelude(next));
+                let into = match next {
+                    Some(next) => function_at(next).filter(|&name| name != function),
+                    None => None,
+                };
+                if falls_through && (next.is_none() || into.is_some()) {
+                    warnings.push(Warning::MissingReturn {
+                        function: function.clone(),
+                        into: into.cloned(),
+                        line: program.lines[last],
+                    });
+                    break;
+                }
+
+                for edge in self.edges.iter().filter(|edge| edge.from == block && edge.kind != EdgeKind::Call) {
+                    let other_function = function_at(edge.to).is_some_and(|name| name != function);
+                    if !other_function && seen.insert(edge.to) {
+                        pending.push(edge.to);
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for name in block.labels.iter() {
+                label.push_str(&format!("{}:\\l", escape(name)));
+            }
+            for &instruction in block.instructions.iter() {
+                label.push_str(&format!("    {}\\l", escape(self.program.text(instruction))));
+            }
+            dot.push_str(&format!("    b{} [label=\"{}\"];\n", index, label));
+        }
+
+        for edge in self.edges.iter() {
+            dot.push_str(&format!("    b{} -> b{} [label=\"{}\"];\n", edge.from, edge.to, edge.kind));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_to_dot() {
+        let mut program = Program::new("mov a, 5\ncall half\nmsg 'a = ', a\nend\n\nhalf:\n    div a, 2\n    cmp a, 0\n    jne half\n    ret\n");
+        program.parse();
+        let cfg = Cfg::build(&program);
+
+        assert_eq!(4, cfg.blocks.len());
+        assert_eq!(vec![String::from("half")], cfg.blocks[2].labels);
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("    b0 [label=\"    mov a, 5\\l    call half\\l\"];\n"));
+        assert!(dot.contains("    b1 [label=\"    msg 'a = ', a\\l    end\\l\"];\n"));
+        assert!(dot.contains("    b2 [label=\"half:\\l    div a, 2\\l    cmp a, 0\\l    jne half\\l\"];\n"));
+        assert!(dot.contains("    b0 -> b2 [label=\"call\"];\n"));
+        assert!(dot.contains("    b0 -> b1 [label=\"fallthrough\"];\n"));
+        assert!(dot.contains("    b2 -> b2 [label=\"branch\"];\n"));
+        assert!(dot.contains("    b2 -> b3 [label=\"fallthrough\"];\n"));
+        assert!(!dot.contains("b3 ->"));
+    }
+
+    #[test]
+    fn check_successors() {
+        let mut program = Program::new("mov a, 3\njmp loop\nmsg 'skipped'\nloop:\n    dec a\n    cmp a, 0\n    jne loop\n    call done\n    end\ndone:\n    ret\n");
+        program.parse();
+        let successors = |index: usize| program.instructions[index].successors(index, &program);
+
+        assert_eq!(vec![1], successors(0));
+        assert_eq!(vec![4], successors(1));
+        assert_eq!(vec![4, 7], successors(6));
+        assert_eq!(vec![10, 8], successors(7));
+        assert!(successors(8).is_empty());
+        assert!(successors(10).is_empty());
+    }
+
+    #[test]
+    fn check_missing_returns() {
+        let mut program = Program::new("\ncall  func1\ncall  print\nend\n\nfunc1:\n    call  func2\n    ret\n\nfunc2:\n    ret\n\nprint:\n    msg 'This program should return null'\n");
+        program.parse();
+        let warning = Warning::MissingReturn { function: String::from("print"), into: None, line: 14 };
+        assert_eq!(vec![warning], Cfg::build(&program).missing_returns());
+
+        let mut program = Program::new("call first\ncall second\nend\nfirst:\n    cmp a, 0\n    je done\n    inc a\nsecond:\n    dec a\ndone:\n    ret\n");
+        program.parse();
+        let warning = Warning::MissingReturn { function: String::from("first"), into: Some(String::from("second")), line: 7 };
+        assert_eq!(vec![warning], Cfg::build(&program).missing_returns());
+
+        // Falling into a label that is only jumped to stays inside the function, so do calls and tail jumps
+        let mut program = Program::new("call first\ncall second\nend\nfirst:\n    call __abs\nloop:\n    dec a\n    jne loop\n    jmp second\nsecond:\n    ret\n");
+        program.parse();
+        assert!(Cfg::build(&program).missing_returns().is_empty());
+
+        // Functions only called with a condition are checked too
+        let mut program = Program::new("cmp a, 0\ncalleq first\ncallne second\nend\nfirst:\n    inc a\nsecond:\n    ret\n");
+        program.parse();
+        let warning = Warning::MissingReturn { function: String::from("first"), into: Some(String::from("second")), line: 6 };
+        assert_eq!(vec![warning], Cfg::build(&program).missing_returns());
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use crate::{InterpretError, Warning};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // 1-based source line
+    pub line: Option<usize>,
+    // Byte range within the line, the whole line is underlined when missing
+    pub span: Option<Range<usize>>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self { severity: Severity::Error, message: message.into(), line: None, span: None }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self { severity: Severity::Warning, message: message.into(), line: None, span: None }
+    }
+
+    pub fn at(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn with_span(mut self, span: Range<usize>) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    // Render in the style of rustc, quoting the offending source line
+    pub fn render(&self, source: &str) -> String {
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+
+        let (line, text) = match self.line.and_then(|line| Some((line, source.lines().nth(line.checked_sub(1)?)?))) {
+            Some(found) => found,
+            None => return out,
+        };
+
+        let span = match &self.span {
+            Some(span) => span.clone(),
+            None => {
+                let start = text.len() - text.trim_start().len();
+                start..text.trim_end().len().max(start)
+            }
+        };
+
+        let gutter = " ".repeat(line.to_string().len());
+        out.push_str(&format!("{}--> line {}\n", gutter, line));
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line, text));
+        out.push_str(&format!(
+            "{} | {}{}\n",
+            gutter,
+            " ".repeat(span.start),
+            "^".repeat(span.len().max(1))
+        ));
+        out
+    }
+}
+
+impl From<&Warning> for Diagnostic {
+    fn from(warning: &Warning) -> Self {
+        Diagnostic::warning(warning.to_string()).at(warning.line())
+    }
+}
+
+impl From<&InterpretError> for Diagnostic {
+    fn from(error: &InterpretError) -> Self {
+        let diagnostic = Diagnostic::error(error.to_string());
+        match error.line() {
+            Some(line) => diagnostic.at(line),
+            None => diagnostic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_render() {
+        let source = "mov a, 1\n    mvo b, 2\nend\n";
+
+        let diagnostic = Diagnostic::error("unknown instruction `mvo`").at(2).with_span(4..7);
+        assert_eq!(
+            "error: unknown instruction `mvo`\n --> line 2\n  |\n2 |     mvo b, 2\n  |     ^^^\n",
+            diagnostic.render(source)
+        );
+
+        let diagnostic = Diagnostic::warning("suspicious line").at(2);
+        assert_eq!(
+            "warning: suspicious line\n --> line 2\n  |\n2 |     mvo b, 2\n  |     ^^^^^^^^\n",
+            diagnostic.render(source)
+        );
+
+        assert_eq!("error: no input left to read\n", Diagnostic::from(&InterpretError::InputExhausted).render(source));
+    }
+}
@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpretError {
+    TooManyRegisters { limit: usize, register: String },
+    InvalidJumpTarget(i64),
+    InvalidLiteral { literal: String, line: usize },
+    InputExhausted,
+    DivisionByZero { register: String, line: usize },
+    Overflow { register: String, line: usize },
+    InvalidShift { count: i64, line: usize },
+    InvalidAddress(i64),
+    EmptyValueStack,
+    StackUnderflow { line: usize },
+    StackOverflow { depth: usize, line: usize },
+    InvalidRepeatCount { count: String, line: usize },
+    InvalidConstant { value: String, line: usize },
+    RecursiveMacro { name: String, line: usize },
+    ExpansionTooLarge { limit: usize, line: usize },
+    MacroArguments { name: String, expected: usize, found: usize, line: usize },
+    UnknownInstruction { mnemonic: String, line: usize },
+    BadOperands { mnemonic: String, expected: RangeInclusive<usize>, found: usize, line: usize },
+    UndefinedLabel(String),
+    UnknownBank { bank: String, line: usize },
+    ReservedName { name: String, line: usize },
+    NotWritable { mnemonic: String, operand: String, line: usize },
+    StepLimitExceeded(u64),
+    StateMismatch,
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpretError::TooManyRegisters { limit, register } => {
+                write!(f, "cannot create register `{}`: limit of {} registers reached", register, limit)
+            }
+            InterpretError::InvalidJumpTarget(target) => {
+                write!(f, "jump target {} is not an instruction of the program", target)
+            }
+            InterpretError::InvalidLiteral { literal, .. } if literal.starts_with('\'') => {
+                write!(f, "invalid literal {}: expected a character or up to 8 ASCII characters", literal)
+            }
+            InterpretError::InvalidLiteral { literal, .. } => {
+                write!(f, "invalid literal `{}`: expected a decimal, `0x` hexadecimal or `0b` binary number that fits the operand", literal)
+            }
+            InterpretError::InputExhausted => write!(f, "no input left to read"),
+            InterpretError::DivisionByZero { register, .. } => write!(f, "division by zero in `{}`", register),
+            InterpretError::Overflow { register, .. } => write!(f, "result doesn't fit into `{}`", register),
+            InterpretError::InvalidShift { count, .. } => write!(f, "shift count {} is outside of 0 to 63", count),
+            InterpretError::InvalidAddress(address) => write!(f, "memory address {} is out of range", address),
+            InterpretError::InvalidRepeatCount { count, .. } => {
+                write!(f, "`times` count `{}` must be a number up to 10000", count)
+            }
+            InterpretError::InvalidConstant { value, .. } => {
+                write!(f, "constant value `{}` must be a number, a character literal or an earlier constant", value)
+            }
+            InterpretError::RecursiveMacro { name, .. } => write!(f, "macro `{}` expands into itself", name),
+            InterpretError::ExpansionTooLarge { limit, .. } => write!(f, "line expands into more than {} instructions", limit),
+            InterpretError::MacroArguments { name, expected, found, .. } => {
+                write!(f, "macro `{}` takes {} arguments, not {}", name, expected, found)
+            }
+            InterpretError::UnknownInstruction { mnemonic, .. } => write!(f, "unknown instruction `{}`", mnemonic),
+            InterpretError::BadOperands { mnemonic, expected, found, .. } => {
+                if expected.start() == expected.end() {
+                    write!(f, "`{}` takes {} operands, found {}", mnemonic, expected.start(), found)
+                } else {
+                    write!(f, "`{}` takes {} to {} operands, found {}", mnemonic, expected.start(), expected.end(), found)
+                }
+            }
+            InterpretError::UndefinedLabel(label) => write!(f, "label `{}` is not defined", label),
+            InterpretError::UnknownBank { bank, .. } => write!(f, "register bank `{}` was never saved", bank),
+            InterpretError::ReservedName { name, .. } => {
+                write!(f, "`{}` starts with `__`, which is reserved for the built-in subroutines", name)
+            }
+            InterpretError::NotWritable { mnemonic, operand, .. } => {
+                write!(f, "`{}` needs a register or memory destination, found `{}`", mnemonic, operand)
+            }
+            InterpretError::StepLimitExceeded(limit) => write!(f, "step limit of {} reached", limit),
+            InterpretError::StateMismatch => write!(f, "the saved state belongs to a different program"),
+            InterpretError::StackOverflow { depth, .. } => write!(f, "calls nested deeper than {} levels", depth),
+            InterpretError::StackUnderflow { .. } => write!(f, "`ret` without a matching `call`"),
+            InterpretError::EmptyValueStack => write!(f, "the value stack holds fewer values than needed"),
+        }
+    }
+}
+
+impl InterpretError {
+    // Source line the error points at, when it is known
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            InterpretError::InvalidRepeatCount { line, .. }
+            | InterpretError::InvalidConstant { line, .. }
+            | InterpretError::RecursiveMacro { line, .. }
+            | InterpretError::ExpansionTooLarge { line, .. }
+            | InterpretError::MacroArguments { line, .. }
+            | InterpretError::UnknownInstruction { line, .. }
+            | InterpretError::ReservedName { line, .. }
+            | InterpretError::NotWritable { line, .. }
+            | InterpretError::InvalidLiteral { line, .. }
+            | InterpretError::BadOperands { line, .. } => Some(*line),
+            // Built-in subroutines have no source line
+            InterpretError::DivisionByZero { line, .. }
+            | InterpretError::Overflow { line, .. }
+            | InterpretError::InvalidShift { line, .. }
+            | InterpretError::UnknownBank { line, .. }
+            | InterpretError::StackOverflow { line, .. }
+            | InterpretError::StackUnderflow { line } => {
+                Some(*line).filter(|&line| line > 0)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Error for InterpretError {}
@@ -1,10 +1,53 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod cfg;
+mod diagnostic;
+mod error;
+mod sink;
+mod state;
+mod symbol;
+mod trace;
+mod warning;
+
+pub use cfg::{BasicBlock, Cfg, Edge, EdgeKind};
+pub use diagnostic::{Diagnostic, Severity};
+pub use error::InterpretError;
+pub use sink::{BufferedSink, LimitedSink, OutputSink};
+use sink::NoSink;
+pub use state::State;
+pub use symbol::{Symbol, SymbolMap, SymbolTable};
+pub use trace::{Divergence, Trace, TraceStep};
+pub use warning::Warning;
+
+// Runs an instruction registered with `Program::with_commands`. It gets the values of the operands,
+// a returned value is stored in `a`
+pub type Handler<'a> = Box<dyn Fn(&[i64]) -> Option<i64> + 'a>;
 
 pub struct Program<'a> {
     source: &'a str,
     pub instructions: Vec<Instruction>,
-    functions: HashMap<String, usize>,
+    lines: Vec<usize>,
+    // Every name the instructions use, dropped with the program
+    names: SymbolTable,
+    functions: SymbolMap<usize>,
+    // Values defined with `name equ value`
+    constants: SymbolMap<i64>,
+    // Terms of every memory operand, see `memory_terms`
+    addresses: SymbolMap<Vec<(bool, Symbol)>>,
+    warnings: Vec<Warning>,
+    errors: Vec<InterpretError>,
+    prelude: bool,
+    // Report unknown mnemonics as errors instead of parsing them as no-ops
+    strict: bool,
+    // Extension instructions, by lowercase mnemonic
+    commands: HashMap<String, Handler<'a>>,
+    // Index of the first built-in instruction, if any were added
+    prelude_start: Option<usize>,
 }
 
 impl<'a> Program<'a> {
@@ -12,354 +55,4479 @@ impl<'a> Program<'a> {
         Self {
             source,
             instructions: Vec::new(),
-            functions: HashMap::new(),
+            lines: Vec::new(),
+            names: SymbolTable::default(),
+            functions: SymbolMap::default(),
+            constants: SymbolMap::default(),
+            addresses: SymbolMap::default(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            prelude: true,
+            strict: false,
+            commands: HashMap::new(),
+            prelude_start: None,
         }
     }
 
+    // Don't add the built-in `__gcd`, `__pow` and `__abs` subroutines
+    pub fn without_prelude(mut self) -> Self {
+        self.prelude = false;
+        self
+    }
+
+    // Make a line with an unknown mnemonic, like the typo `mvo a, 5`, a parse error instead of a no-op
+    pub fn with_strict_parsing(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    // Run lines whose mnemonic isn't an instruction but one of the `commands` through its handler
+    pub fn with_commands(mut self, commands: HashMap<String, Handler<'a>>) -> Self {
+        self.commands = commands.into_iter().map(|(name, handler)| (name.to_ascii_lowercase(), handler)).collect();
+        self
+    }
+
     fn parse(&mut self) {
-        // Clean code and make instructions
-        self.instructions = self.source.lines()
-            .map(|x| {
-                let mut clean = String::new();
-                // Remove comment
-                match x.find(';') {
-                    Some(com_pos) => clean.push_str(&x[..com_pos]),
-                    None => clean.push_str(&x)
+        let mut macros = HashMap::new();
+        let mut expansions = 0;
+
+        // Clean code and make instructions, remembering the source line of every instruction
+        for (number, line) in self.source.lines().enumerate() {
+            let line = clean_line(line);
+            if let Some((name, definition)) = split_macro(line) {
+                macros.insert(name.to_string(), definition);
+                self.instructions.push(Instruction::Nop);
+                self.lines.push(number + 1);
+                continue;
+            }
+            if let Some((name, value)) = split_equ(line) {
+                match self.constant(value) {
+                    Some(value) => {
+                        self.constants.insert(self.names.intern(name), value);
+                    }
+                    None => self.errors.push(InterpretError::InvalidConstant { value: value.to_string(), line: number + 1 }),
+                }
+                self.instructions.push(Instruction::Nop);
+                self.lines.push(number + 1);
+                continue;
+            }
+            let (count, line) = match split_times(line) {
+                Some((count, rest)) => match count.parse::<u64>() {
+                    Ok(count) if count <= MAX_TIMES => (count, rest),
+                    _ => {
+                        self.errors.push(InterpretError::InvalidRepeatCount { count: count.to_string(), line: number + 1 });
+                        (1, "")
+                    }
+                },
+                None => (1, line),
+            };
+            let mut budget = MAX_EXPANDED;
+            for _ in 0..count {
+                let lines = match expand(&macros, line, &mut expansions, &mut budget, &mut Vec::new(), number + 1) {
+                    Ok(lines) => lines,
+                    Err(error) => {
+                        self.errors.push(error);
+                        break;
+                    }
+                };
+                for line in lines {
+                    let mut instruction = Instruction::parse(&line, &mut self.names);
+                    for literal in instruction.numbers() {
+                        if parse_integer(literal).is_none() {
+                            self.errors.push(InterpretError::InvalidLiteral { literal: literal.to_string(), line: number + 1 });
+                        }
+                    }
+                    if !line.is_empty() {
+                        let (mnemonic, params) = tokenize(&line, &mut self.names);
+                        let found = params.iter().filter(|param| !param.is_empty()).count();
+                        let mnemonic = mnemonic.to_string();
+                        let command = mnemonic.to_ascii_lowercase();
+                        // A count isn't a value, so it's left out of `numbers`
+                        if found == 1 && matches!(instruction.unconditional(), Instruction::Ret(_) | Instruction::Pad(_)) && parse_count(&params[0]).is_none() {
+                            self.errors.push(InterpretError::InvalidLiteral { literal: params[0].to_string(), line: number + 1 });
+                        }
+                        match operand_counts(&mnemonic) {
+                            Some(expected) if !expected.contains(&found) || written_operands(&params).is_none() => {
+                                self.errors.push(InterpretError::BadOperands { mnemonic, expected, found, line: number + 1 });
+                            }
+                            None if instruction == Instruction::Nop && self.commands.contains_key(&command) => {
+                                let args = params.into_iter().filter(|param| !param.is_empty()).collect();
+                                instruction = Instruction::Command(self.names.intern(&command), args);
+                            }
+                            None if self.strict && instruction == Instruction::Nop => {
+                                self.errors.push(InterpretError::UnknownInstruction { mnemonic, line: number + 1 });
+                            }
+                            _ => {}
+                        }
+                    }
+                    self.instructions.push(instruction);
+                    self.lines.push(number + 1);
                 }
-                Instruction::from(clean.trim().to_string())
+            }
+        }
+
+        // Append the built-in subroutines when the program calls one it doesn't define itself
+        let defined: HashSet<Symbol> = self.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Function(name) => Some(name.clone()),
+                _ => None
             })
             .collect();
+        let calls_prelude = self.instructions.iter().any(|instruction| match instruction.unconditional() {
+            Instruction::Call(label) | Instruction::CallExpr(_, label) => label.starts_with("__") && !defined.contains(label),
+            _ => false
+        });
+        if self.prelude && calls_prelude {
+            // Labels and scratch registers of the built-in subroutines start with `__`, the program's
+            // own would retarget their jumps or be clobbered. Only the subroutines themselves may be replaced
+            let mut reserved: Vec<&str> = Vec::new();
+            for (index, instruction) in self.instructions.iter().enumerate() {
+                let label = match instruction {
+                    Instruction::Function(name) => Some(name),
+                    other => other.label(),
+                };
+                let names = label.filter(|label| !BUILT_INS.contains(&label.as_str())).map(Symbol::as_str).into_iter()
+                    .chain(instruction.registers());
+                for name in names.filter(|name| name.starts_with("__")) {
+                    if !reserved.contains(&name) {
+                        reserved.push(name);
+                        self.errors.push(InterpretError::ReservedName { name: name.to_string(), line: self.lines[index] });
+                    }
+                }
+            }
+
+            self.prelude_start = Some(self.instructions.len());
+            for line in PRELUDE.lines() {
+                self.instructions.push(Instruction::parse(clean_line(line), &mut self.names));
+                self.lines.push(0);
+            }
+        }
 
-        // Find functions
+        // Find functions, labels of the program take precedence over built-in ones
         for (index, instruction) in self.instructions.iter().enumerate() {
             if let Instruction::Function(name) = instruction {
-                self.functions.insert(name.to_owned(), index + 1);
+                if self.lines[index] == 0 {
+                    self.functions.entry(name.clone()).or_insert(index + 1);
+                } else {
+                    self.functions.insert(name.clone(), index + 1);
+                }
             }
         }
-    }
-}
 
-#[derive(Debug)]
-pub enum Instruction {
-    Mov(String, String),
-    Inc(String),
-    Dec(String),
-    Add(String, String),
-    Sub(String, String),
-    Mul(String, String),
-    Div(String, String),
-    Function(String),
-    Call(String),
-    Cmp(String, String),
-    Jmp(String),
-    Jne(String),
-    Je(String),
-    Jge(String),
-    Jg(String),
-    Jle(String),
-    Jl(String),
-    Msg(Vec<String>),
-    Ret,
-    End,
-    Nop,
-}
+        for instruction in self.instructions.iter() {
+            for operand in instruction.operands() {
+                if operand.starts_with('[') && !self.addresses.contains_key(operand) {
+                    self.addresses.insert(operand.clone(), memory_terms(operand, &mut self.names));
+                }
+            }
+        }
 
-impl From<String> for Instruction {
-    fn from(raw_instruction: String) -> Self {
-        if raw_instruction == "" {
-            return Instruction::Nop;
+        // Every jump and call must aim at a label, each missing one is reported once
+        let mut missing: Vec<Symbol> = Vec::new();
+        for instruction in self.instructions.iter() {
+            let Some(label) = instruction.label() else {
+                continue;
+            };
+            if !self.functions.contains_key(label) && !missing.contains(label) {
+                missing.push(label.clone());
+            }
         }
+        self.errors.extend(missing.iter().map(|label| InterpretError::UndefinedLabel(label.to_string())));
 
-        let args: Vec<&str> = raw_instruction.split_whitespace().collect();
-        let raw_params = raw_instruction.replace(&args[0], "");
-
-        let params: Vec<&str> = raw_params.trim()
-            .split(',')
-            .fold(vec![], |mut res, curr: &str| {
-                res.push(curr.trim());
-                res
-            });
-
-        match args[0] {
-            "mov" => Instruction::Mov(params[0].to_string(), params[1].to_string()),
-            "inc" => Instruction::Inc(params[0].to_string()),
-            "dec" => Instruction::Dec(params[0].to_string()),
-            "add" => Instruction::Add(params[0].to_string(), params[1].to_string()),
-            "sub" => Instruction::Sub(params[0].to_string(), params[1].to_string()),
-            "mul" => Instruction::Mul(params[0].to_string(), params[1].to_string()),
-            "div" => Instruction::Div(params[0].to_string(), params[1].to_string()),
-            "call" => Instruction::Call(params[0].to_string()),
-            "cmp" => Instruction::Cmp(params[0].to_string(), params[1].to_string()),
-            "jmp" => Instruction::Jmp(params[0].to_string()),
-            "jne" => Instruction::Jne(params[0].to_string()),
-            "je" => Instruction::Je(params[0].to_string()),
-            "jge" => Instruction::Jge(params[0].to_string()),
-            "jg" => Instruction::Jg(params[0].to_string()),
-            "jle" => Instruction::Jle(params[0].to_string()),
-            "jl" => Instruction::Jl(params[0].to_string()),
-            "msg" => Instruction::Msg(params.iter().map(|x| x.to_string()).collect()),
-            "ret" => Instruction::Ret,
-            "end" => Instruction::End,
-            other => if other.ends_with(":") {
-                Instruction::Function(other.trim_matches(':').to_string())
-            } else {
-                Instruction::Nop
+        // Labels named like a register make jumps and operands ambiguous to read
+        let registers: HashSet<&str> = self.instructions.iter()
+            .flat_map(|instruction| instruction.registers())
+            .collect();
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if let Instruction::Function(name) = instruction {
+                if registers.contains(name.as_str()) && self.lines[index] > 0 {
+                    self.warnings.push(Warning::LabelShadowsRegister { label: name.to_string(), line: self.lines[index] });
+                }
             }
         }
+
+        let missing_returns = Cfg::build(self).missing_returns();
+        self.warnings.extend(missing_returns);
+
+        let unwritable: Vec<InterpretError> = self.unwritable().into_iter()
+            .map(|(index, mnemonic, operand)| InterpretError::NotWritable { mnemonic, operand: operand.to_string(), line: self.lines[index] })
+            .collect();
+        self.errors.extend(unwritable);
     }
-}
 
-pub struct Interpreter<'a> {
-    stack: Vec<usize>,
-    register: HashMap<String, i64>,
-    rip: usize,
-    zf: u8,
-    cf: u8,
-    out: String,
-    pub program: Program<'a>,
-}
+    // Value of a constant definition: an integer, a character literal or an earlier constant
+    fn constant(&self, value: &str) -> Option<i64> {
+        match parse_integer(value) {
+            Some(value) => Some(value),
+            _ if value.starts_with('\'') => pack_chars(value),
+            _ => self.names.lookup(value).and_then(|name| self.constants.get(&name).copied()),
+        }
+    }
 
-impl Display for Interpreter<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let delimiter = "-".repeat(20);
+    // Index a jump or call to `label` continues at
+    fn target(&self, label: &Symbol) -> Result<usize, InterpretError> {
+        self.functions.get(label).copied().ok_or_else(|| InterpretError::UndefinedLabel(label.to_string()))
+    }
 
-        // Registers
-        writeln!(f, "Registers:")?;
-        writeln!(f, "{}", delimiter)?;
-        for (key, value) in self.register.iter() {
-            writeln!(f, "{:<5}: {:<10}", key, value)?;
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Problems found while parsing, running the program fails with the first one
+    pub fn errors(&self) -> &[InterpretError] {
+        &self.errors
+    }
+
+    // Check that instructions only write into registers or memory. Parsing reports the same as
+    // `NotWritable` errors, these point at the operand
+    pub fn validate_operands(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (index, mnemonic, dst) in self.unwritable() {
+            let mut diagnostic = Diagnostic::error(format!(
+                "`{}` needs a register or memory destination, found `{}`",
+                mnemonic, dst
+            )).at(self.lines[index]);
+            let text = self.source.lines().nth(self.lines[index] - 1).unwrap_or("");
+            if let Some(start) = text.find(&mnemonic).and_then(|start| {
+                Some(start + mnemonic.len() + text[start + mnemonic.len()..].find(dst)?)
+            }) {
+                diagnostic = diagnostic.with_span(start..start + dst.len());
+            }
+            diagnostics.push(diagnostic);
         }
-        writeln!(f, "{}", delimiter)?;
+        diagnostics
+    }
 
-        // Stack
-        write!(f, "\nStack:")?;
-        if !self.stack.is_empty() {
-            writeln!(f, "{}", delimiter)?;
-            for (num, line) in self.stack.iter().enumerate() {
-                writeln!(f, "{:<10}: {:<10}", num, line)?;
+    // Index, mnemonic and operand of every destination that isn't a register or memory
+    fn unwritable(&self) -> Vec<(usize, String, &str)> {
+        let mut unwritable = Vec::new();
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            for dst in instruction.destinations() {
+                let writable = (is_register(dst) && self.names.lookup(dst).is_none_or(|dst| !self.constants.contains_key(&dst)))
+                    || dst.starts_with('[')
+                    || (dst == "rip" && matches!(instruction.unconditional(), Instruction::Mov(..)));
+                if !writable {
+                    let mnemonic = instruction.to_string().split_whitespace().next().unwrap_or("").to_string();
+                    unwritable.push((index, mnemonic, dst));
+                }
             }
-            writeln!(f, "{}", delimiter)?;
-        } else {
-            write!(f, "Empty\n")?;
         }
+        unwritable
+    }
 
-        // Flags
-        write!(f, "\nFlags:")?;
-        write!(f, "\n{}\n", delimiter)?;
-        write!(f, "{:<2}: {:<10}\n", "ZF", self.zf)?;
-        write!(f, "{:<2}: {:<10}", "CF", self.cf)?;
-        write!(f, "\n{}\n", delimiter)?;
+    // Warnings, parse errors and operand problems of the program, warnings first
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut diagnostics: Vec<Diagnostic> = self.warnings.iter().map(Diagnostic::from).collect();
+        // Unwritable destinations come from `validate_operands`, which points at the operand
+        diagnostics.extend(self.errors.iter()
+            .filter(|error| !matches!(error, InterpretError::NotWritable { .. }))
+            .map(Diagnostic::from));
+        diagnostics.extend(self.validate_operands());
+        diagnostics
+    }
 
-        // Output
-        write!(f, "\nOutput: {}\n", self.out)?;
+    // Every diagnostic rendered against the source, followed by a line counting them. Empty when
+    // there is nothing to report
+    pub fn pretty_errors(&self) -> String {
+        let diagnostics = self.diagnostics();
+        let count = |severity| diagnostics.iter().filter(|diagnostic| diagnostic.severity == severity).count();
+        let counts: Vec<String> = [(count(Severity::Error), "error"), (count(Severity::Warning), "warning")].iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|&(count, noun)| format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" }))
+            .collect();
+        if counts.is_empty() {
+            return String::new();
+        }
 
-        // Pointer
-        write!(f, "\nRIP: {}\n", self.rip)?;
-        Ok(())
+        let mut report: String = diagnostics.iter().map(|diagnostic| diagnostic.render(self.source)).collect();
+        report.push_str(&format!("{} found\n", counts.join(" and ")));
+        report
     }
-}
 
-impl<'a> Interpreter<'a> {
-    fn new(source: &'a str) -> Self {
-        Self {
-            stack: Vec::new(),
-            register: HashMap::new(),
-            rip: 0,
-            zf: 0,
-            cf: 0,
-            out: String::new(),
-            program: Program::new(source),
-        }
+    pub fn symbols(&self) -> Vec<(String, usize)> {
+        self.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Function(name) => Some((name.to_string(), self.functions[name])),
+                _ => None
+            })
+            .collect()
     }
 
-    pub fn interpret(input: &'a str) -> (Self, Option<String>) {
-        let mut interpreter = Interpreter::new(input);
-        interpreter.program.parse();
-        let output = interpreter.run();
-        (interpreter, output)
+    // Every label leading to the instruction at `index`. Adjacent labels all lead to the same
+    // instruction, so all of them are returned in source order, the first defined one first
+    pub fn labels_at(&self, index: usize) -> Vec<&str> {
+        self.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Function(name) if self.resolve(self.functions[name]) == Some(index) => Some(name.as_str()),
+                _ => None
+            })
+            .collect()
     }
 
-    fn run(&mut self) -> Option<String> {
-        loop {
-            match self.program.instructions.get(self.rip)? {
-                Instruction::Mov(dst, src) => {
-                    let src_value = self.constant_or_register(src);
-                    *self.register.entry(dst.into()).or_insert(0) = src_value;
-                    self.rip += 1;
-                }
+    // First executable instruction at or after `index`
+    fn resolve(&self, index: usize) -> Option<usize> {
+        (index..self.instructions.len()).find(|&i| self.instructions[i].is_executable())
+    }
 
-                Instruction::Inc(dst) => {
-                    *self.register.entry(dst.into()).or_insert(0) += 1;
-                    self.rip += 1;
+    // Labels that are referenced, in source order. A label is an entry point when it is `call`ed
+    // anywhere, also with a condition suffix like `callne`, otherwise it is internal to the routine jumping to it
+    pub fn entry_points(&self) -> Vec<(String, LabelKind)> {
+        let mut called = HashSet::new();
+        let mut jumped = HashSet::new();
+        for instruction in self.instructions.iter() {
+            match instruction.unconditional() {
+                Instruction::Call(label) | Instruction::CallExpr(_, label) => {
+                    called.insert(label.as_str());
                 }
-
-                Instruction::Dec(dst) => {
-                    *self.register.entry(dst.into()).or_insert(0) -= 1;
-                    self.rip += 1;
+                Instruction::Jmp(label)
+                | Instruction::Jne(label)
+                | Instruction::Je(label)
+                | Instruction::Jge(label)
+                | Instruction::Jg(label)
+                | Instruction::Jle(label)
+                | Instruction::Jl(label)
+                | Instruction::Jcxz(_, label) => {
+                    jumped.insert(label.as_str());
                 }
+                _ => {}
+            }
+        }
 
-                Instruction::Add(dst, src) => {
-                    let src_value = self.constant_or_register(src);
-                    *self.register.entry(dst.into()).or_insert(0) += src_value;
-                    self.rip += 1;
-                }
+        self.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Function(name) if called.contains(name.as_str()) => Some((name.to_string(), LabelKind::Entry)),
+                Instruction::Function(name) if jumped.contains(name.as_str()) => Some((name.to_string(), LabelKind::Internal)),
+                _ => None
+            })
+            .collect()
+    }
 
-                Instruction::Sub(dst, src) => {
-                    let src_value = self.constant_or_register(src);
-                    *self.register.entry(dst.into()).or_insert(0) -= src_value;
-                    self.rip += 1;
-                }
+    // Every source line with the tokens the parser split it into and the instruction it became
+    pub fn dump_parse_tree(&self) -> String {
+        let mut dump = String::new();
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let Some(line) = self.line_of(index) else {
+                continue;
+            };
+            let raw = self.source.lines().nth(line - 1).unwrap_or("");
+            let (mnemonic, params) = tokenize(self.text(index), &mut SymbolTable::default());
+            let tokens: Vec<&str> = std::iter::once(mnemonic)
+                .chain(params.iter().map(|param| &**param))
+                .filter(|token| !token.is_empty())
+                .collect();
+            dump.push_str(&format!("{:>4} | {}\n     | tokens: {:?}\n     | {:?}\n", line, raw, tokens, instruction));
+        }
+        dump
+    }
 
-                Instruction::Mul(dst, src) => {
-                    let src_value = self.constant_or_register(src);
-                    *self.register.entry(dst.into()).or_insert(0) *= src_value;
-                    self.rip += 1;
-                }
+    // 1-based source line the instruction at `index` came from, built-in instructions have none
+    pub fn line_of(&self, index: usize) -> Option<usize> {
+        self.lines.get(index).copied().filter(|&line| line > 0)
+    }
 
-                Instruction::Div(dst, src) => {
-                    let src_value = self.constant_or_register(src);
-                    *self.register.entry(dst.into()).or_insert(0) /= src_value;
-                    self.rip += 1;
-                }
+    // First executable instruction at or after the source `line`
+    fn index_at_line(&self, line: usize) -> usize {
+        (0..self.instructions.len())
+            .find(|&index| self.lines[index] >= line && self.instructions[index].is_executable())
+            .unwrap_or(self.instructions.len())
+    }
 
-                Instruction::Call(label) => {
-                    self.stack.push(self.rip + 1);
-                    self.rip = *self.program.functions.get(label).unwrap();
-                }
+    // Cleaned source text of the instruction at `index`
+    fn text(&self, index: usize) -> &'a str {
+        let line = match self.prelude_start {
+            Some(start) if index >= start => PRELUDE.lines().nth(index - start),
+            _ => self.source.lines().nth(self.lines[index] - 1),
+        };
+        let line = line.map(clean_line).unwrap_or("");
+        split_times(line).map_or(line, |(_, rest)| rest)
+    }
+}
 
-                Instruction::Cmp(dst, src) => {
-                    // Reset flags
-                    self.zf = 0;
-                    self.cf = 0;
+// Built-in subroutines, see `Program::without_prelude`
+const PRELUDE: &str = include_str!("prelude.asm");
 
-                    let dst_value = self.constant_or_register(dst);
-                    let src_value = self.constant_or_register(src);
+// Subroutines of the prelude a program may call or define itself
+const BUILT_INS: [&str; 3] = ["__gcd", "__pow", "__abs"];
 
-                    if dst_value == src_value {
-                        self.zf = 1;
-                    } else if dst_value < src_value {
-                        self.cf = 1;
-                    }
+// Implicit destination of single-operand `mul` and `div`
+const ACCUMULATOR: &str = "a";
 
-                    self.rip += 1;
-                }
+// Upper bound for `times`, so a typo can't expand into millions of instructions
+const MAX_TIMES: u64 = 10_000;
 
-                Instruction::Jmp(label) => {
-                    self.rip = *self.program.functions.get(label).unwrap();
-                }
+// Upper bound for the instructions one line expands into, `times` and nested macros included, since
+// macros using each other grow exponentially
+const MAX_EXPANDED: usize = MAX_TIMES as usize;
 
-                Instruction::Jne(label) => {
-                    if self.zf != 1 {
-                        self.rip = *self.program.functions.get(label).unwrap();
-                    } else {
-                        self.rip += 1;
-                    }
-                }
+// Calls that may be nested unless `with_max_stack_depth` says otherwise
+const MAX_STACK_DEPTH: usize = 1_000_000;
 
-                Instruction::Je(label) => {
-                    if self.zf == 1 {
-                        self.rip = *self.program.functions.get(label).unwrap();
-                    } else {
-                        self.rip += 1;
-                    }
-                }
+// Split `times n instruction` into the count and the repeated instruction
+fn split_times(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix("times")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (count, instruction) = rest.trim_start().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+    Some((count, instruction.trim_start()))
+}
 
-                Instruction::Jge(label) => {
-                    if self.zf == 1 || self.cf == 0 {
-                        self.rip = *self.program.functions.get(label).unwrap();
-                    } else {
-                        self.rip += 1;
-                    }
-                }
+// Split `name equ value` into the constant name and its value
+fn split_equ(line: &str) -> Option<(&str, &str)> {
+    let (name, rest) = line.split_once(char::is_whitespace)?;
+    let value = rest.trim_start().strip_prefix("equ")?;
+    (is_register(name) && value.starts_with(char::is_whitespace)).then(|| (name, value.trim()))
+}
 
-                Instruction::Jg(label) => {
-                    if self.zf == 0 && self.cf == 0 {
-                        self.rip = *self.program.functions.get(label).unwrap();
-                    } else {
-                        self.rip += 1;
-                    }
-                }
+// Lines and parameters of a `macro` definition, along with the labels its lines define
+struct Macro {
+    parameters: Vec<String>,
+    lines: Vec<String>,
+    labels: Vec<String>,
+}
 
-                Instruction::Jle(label) => {
-                    if self.cf == 1 || self.zf == 1 {
-                        self.rip = *self.program.functions.get(label).unwrap();
-                    } else {
-                        self.rip += 1;
-                    }
-                }
+// Split `macro name %a, %b { first | second }` into the macro name and its definition
+fn split_macro(line: &str) -> Option<(&str, Macro)> {
+    let rest = line.strip_prefix("macro")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (head, body) = rest.split_once('{')?;
+    let body = body.trim_end().strip_suffix('}')?;
+    let (name, parameters) = head.trim().split_once(char::is_whitespace).unwrap_or((head.trim(), ""));
+    let lines: Vec<String> = body.split('|').map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect();
+    Some((name, Macro {
+        parameters: parameters.split([',', ' ']).filter(|parameter| !parameter.is_empty()).map(String::from).collect(),
+        labels: lines.iter().filter_map(|line| line.strip_suffix(':')).map(String::from).collect(),
+        lines,
+    }))
+}
 
-                Instruction::Jl(label) => {
-                    if self.cf == 1 {
-                        self.rip = *self.program.functions.get(label).unwrap();
-                    } else {
-                        self.rip += 1;
-                    }
-                }
+// The lines a cleaned line stands for, with macro uses replaced by the macro's lines. Labels defined
+// inside a macro get a `@name.N` suffix per expansion, so they can't clash with labels of the program
+// or of other expansions. `active` holds the macros being expanded, to stop a macro using itself,
+// and every line produced is taken from `budget`
+fn expand(
+    macros: &HashMap<String, Macro>,
+    line: &str,
+    expansions: &mut usize,
+    budget: &mut usize,
+    active: &mut Vec<String>,
+    number: usize,
+) -> Result<Vec<String>, InterpretError> {
+    let (name, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let Some(definition) = macros.get(name) else {
+        *budget = budget.checked_sub(1).ok_or(InterpretError::ExpansionTooLarge { limit: MAX_EXPANDED, line: number })?;
+        return Ok(vec![line.to_string()]);
+    };
+    if active.iter().any(|active| active == name) {
+        return Err(InterpretError::RecursiveMacro { name: name.to_string(), line: number });
+    }
+    let arguments: Vec<&str> = arguments.split(',').map(str::trim).filter(|argument| !argument.is_empty()).collect();
+    if arguments.len() != definition.parameters.len() {
+        return Err(InterpretError::MacroArguments {
+            name: name.to_string(),
+            expected: definition.parameters.len(),
+            found: arguments.len(),
+            line: number,
+        });
+    }
 
-                Instruction::Msg(args) => {
-                    let mut opened = false;
-                    // Concat arguments
-                    let res: String = args.iter().map(|i| {
-                        if i == "'" {
-                            if !opened {
-                                opened = !opened;
-                                String::from(",")
-                            } else {
-                                opened = !opened;
-                                String::from(" ")
-                            }
-                        } else if i.contains("'") {
-                            i.trim_matches('\'').to_string()
-                        } else {
-                            self.constant_or_register(i).to_string()
-                        }
-                    }).collect();
+    *expansions += 1;
+    let suffix = format!("@{}.{}", name, expansions);
+    active.push(name.to_string());
+    let mut lines = Vec::new();
+    for line in definition.lines.iter() {
+        let line = replace_words(line, |word| {
+            match definition.parameters.iter().position(|parameter| parameter == word) {
+                Some(index) => Some(arguments[index].to_string()),
+                None => definition.labels.iter().any(|label| label == word).then(|| format!("{}{}", word, suffix)),
+            }
+        });
+        lines.extend(expand(macros, &line, expansions, budget, active, number)?);
+    }
+    active.pop();
+    Ok(lines)
+}
 
-                    self.out = res;
-                    self.rip += 1;
-                }
+// Replace every word outside quotes for which `replace` gives a replacement. Words are made of
+// letters, digits, `_` and the `%` starting macro parameters
+fn replace_words(line: &str, replace: impl Fn(&str) -> Option<String>) -> String {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '%';
+    let mut result = String::new();
+    let mut rest = line;
+    let mut quoted = false;
+    while let Some(c) = rest.chars().next() {
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        let length = match rest.find(|c: char| !is_word(c)) {
+            Some(0) => c.len_utf8(),
+            Some(length) if !quoted => length,
+            None if !quoted => rest.len(),
+            _ => c.len_utf8(),
+        };
+        let word = &rest[..length];
+        match replace(word).filter(|_| !quoted && is_word(c)) {
+            Some(replacement) => result.push_str(&replacement),
+            None => result.push_str(word),
+        }
+        rest = &rest[length..];
+    }
+    result
+}
 
-                Instruction::Ret => {
-                    self.rip = self.stack.pop().unwrap();
-                }
+// Split a cleaned line into its mnemonic and operands
+fn tokenize<'l>(line: &'l str, names: &mut SymbolTable) -> (&'l str, Vec<Symbol>) {
+    if line.is_empty() {
+        return ("", vec![]);
+    }
+    // Only the leading mnemonic is removed, operands may contain the same text
+    let (mnemonic, raw_params) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
 
-                Instruction::End => {
-                    return Some(self.out.to_owned());
-                }
+    let params: Vec<Symbol> = raw_params.trim()
+        .split(',')
+        .fold(vec![], |mut res, curr: &str| {
+            res.push(names.intern(&normalize_operand(curr)));
+            res
+        });
+    (mnemonic, params)
+}
 
-                Instruction::Function(_) | Instruction::Nop => {
-                    self.rip += 1;
-                }
-            }
+// Number of operands an instruction takes, None for unknown mnemonics. Conditional forms like
+// `movne` take as many as their stem, `msg` and `abort` any number
+fn operand_counts(mnemonic: &str) -> Option<RangeInclusive<usize>> {
+    let mnemonic = mnemonic.to_ascii_lowercase();
+    let counts = match mnemonic.as_str() {
+        "flush" | "end" => 0..=0,
+        "ret" | "nop" => 0..=1,
+        "inc" | "dec" | "not" | "neg" | "abs" | "lahf" | "sahf" | "call" | "push" | "pop" | "cmpz" | "jmp" | "jne"
+        | "jnz" | "je" | "jz" | "jge" | "jg" | "jle" | "jl" | "read" | "in" | "rand" | "sleep" | "ctxsave" | "ctxload" => 1..=1,
+        "mul" | "div" => 1..=2,
+        "mov" | "xchg" | "add" | "sub" | "mod" | "adc" | "sbb" | "and" | "or" | "xor" | "shl" | "shr" | "sar"
+        | "call_expr" | "cmp" | "test" | "jcxz" | "ctxcopy_prefix" => 2..=2,
+        "between" => 3..=3,
+        "mulmod" => 4..=4,
+        "zero" => 1..=usize::MAX,
+        "msg" | "msgerr" | "abort" => 0..=usize::MAX,
+        other => return Condition::split(other).and_then(|(stem, _)| operand_counts(stem)),
+    };
+    Some(counts)
+}
+
+// Number of operands as written, None when one is left out like in `mov a, , b` or `add , 3`.
+// Instructions index their operands by position, so a left out one can't be skipped. Commas
+// inside quotes don't separate operands
+fn written_operands(params: &[Symbol]) -> Option<usize> {
+    if let [only] = params {
+        if only.is_empty() {
+            return Some(0);
+        }
+    }
+    let mut quoted = false;
+    for param in params {
+        if param.is_empty() && !quoted {
+            return None;
+        }
+        quoted ^= param.matches('\'').count() % 2 == 1;
+    }
+    Some(params.len())
+}
+
+// Remove comment and surrounding whitespace, a `;` inside quotes belongs to the text
+fn clean_line(line: &str) -> &str {
+    let mut quoted = false;
+    let comment = line.char_indices().find(|&(_, c)| {
+        if c == '\'' {
+            quoted = !quoted;
         }
+        c == ';' && !quoted
+    });
+    match comment {
+        Some((com_pos, _)) => line[..com_pos].trim(),
+        None => line.trim()
     }
+}
 
-    fn constant_or_register(&self, src: &str) -> i64 {
-        match src.parse::<i64>() {
-            Ok(r) => r,
-            _ => *self.register.get(src).unwrap_or(&0)
+// Suffix for conditional execution like `movne a, b`, checked against the flags of the last `cmp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Condition {
+    const SUFFIXES: [(&'static str, Condition); 6] = [
+        ("eq", Condition::Eq),
+        ("ne", Condition::Ne),
+        ("gt", Condition::Gt),
+        ("ge", Condition::Ge),
+        ("lt", Condition::Lt),
+        ("le", Condition::Le),
+    ];
+
+    // Split `mnemonic` into the instruction mnemonic and its condition suffix
+    fn split(mnemonic: &str) -> Option<(&str, Condition)> {
+        Condition::SUFFIXES.iter()
+            .find_map(|&(suffix, condition)| Some((mnemonic.strip_suffix(suffix)?, condition)))
+            .filter(|(stem, _)| !stem.is_empty())
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Condition::Eq => "equal",
+            Condition::Ne => "not equal",
+            Condition::Gt => "greater",
+            Condition::Ge => "greater or equal",
+            Condition::Lt => "less",
+            Condition::Le => "less or equal",
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (suffix, _) = Condition::SUFFIXES.iter().find(|(_, condition)| condition == self).unwrap();
+        write!(f, "{}", suffix)
+    }
+}
 
-    #[test]
-    fn check_interpreter() {
-        let programs_list = &[
-            "\n; My first program\nmov  a, 5\ninc  a\ncall function\nmsg  '(5+1)/2 = ', a    ; output message\nend\n\nfunction:\n    div  a, 2\n    ret\n",
-            "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n",
-            "\ncall  func1\ncall  print\nend\n\nfunc1:\n    call  func2\n    ret\n\nfunc2:\n    ret\n\nprint:\n    msg 'This program should return null'\n",
-            "\n            mov a, 173   ; instruction mov a, 173\n            mov k, 88   ; instruction mov k, 88\n            call func\n            msg 'Random result: ', o\n            end\n            func:\n              cmp a, k\n              jne exit\n              mov o, a\n              add o, k\n              ret\n            ; Do nothing\n            exit:\n              msg 'Do nothing'",
-            "\n            mov q, 86   ; instruction mov q, 86\n            mov m, 73   ; instruction mov m, 73\n            call func\n            msg 'Random result: ', g\n            end\n            func:\n              cmp q, m\n              jl exit\n              mov g, q\n              div g, m\n              ret\n            ; Do nothing\n            exit:\n              msg 'Do nothing'"
-        ];
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelKind {
+    // Target of a `call`
+    Entry,
+    // Only reached by jumps
+    Internal,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Mov(Symbol, Symbol),
+    Xchg(Symbol, Symbol),
+    Inc(Symbol),
+    Dec(Symbol),
+    Add(Symbol, Symbol),
+    Sub(Symbol, Symbol),
+    Mul(Symbol, Symbol),
+    Div(Symbol, Symbol),
+    Mod(Symbol, Symbol),
+    Adc(Symbol, Symbol),
+    Sbb(Symbol, Symbol),
+    Mulmod(Symbol, Symbol, Symbol, Symbol),
+    And(Symbol, Symbol),
+    Or(Symbol, Symbol),
+    Xor(Symbol, Symbol),
+    Not(Symbol),
+    Neg(Symbol),
+    Abs(Symbol),
+    Shl(Symbol, Symbol),
+    Shr(Symbol, Symbol),
+    Sar(Symbol, Symbol),
+    Zero(Vec<Symbol>),
+    Lahf(Symbol),
+    Sahf(Symbol),
+    Function(Symbol),
+    Call(Symbol),
+    CallExpr(Symbol, Symbol),
+    Push(Symbol),
+    Pop(Symbol),
+    Cmp(Symbol, Symbol),
+    Test(Symbol, Symbol),
+    Cmpz(Symbol),
+    Between(Symbol, Symbol, Symbol),
+    Jmp(Symbol),
+    Jne(Symbol),
+    Je(Symbol),
+    Jge(Symbol),
+    Jg(Symbol),
+    Jle(Symbol),
+    Jl(Symbol),
+    Jcxz(Symbol, Symbol),
+    Read(Symbol),
+    Rand(Symbol),
+    Sleep(Symbol),
+    Msg(Vec<Symbol>),
+    Abort(Vec<Symbol>),
+    // Like `msg`, but on stderr and without replacing the output
+    MsgErr(Vec<Symbol>),
+    // Copy the registers into the named bank, replace them with a bank, or copy those starting with a prefix from a bank
+    CtxSave(Symbol),
+    CtxLoad(Symbol),
+    CtxCopyPrefix(Symbol, Symbol),
+    // Extension instruction run by a handler of the program
+    Command(Symbol, Vec<Symbol>),
+    Flush,
+    // Return, then drop this many values pushed as arguments
+    Ret(u64),
+    End,
+    Pad(u64),
+    // Runs the inner instruction only when the condition holds
+    Cond(Condition, Box<Instruction>),
+    Nop,
+}
+
+// Size of an encoded instruction: opcode, condition, two unused bytes and four operand ids
+pub const ENCODED_SIZE: usize = 20;
 
-        assert_eq!(Some(String::from("(5+1)/2 = 3")), Interpreter::interpret(&programs_list[0]).1);
-        assert_eq!(Some(String::from("5! = 120")), Interpreter::interpret(&programs_list[1]).1);
-        assert_eq!(None, Interpreter::interpret(&programs_list[2]).1);
-        assert_eq!(None, Interpreter::interpret(&programs_list[3]).1);
-        assert_eq!(Some(String::from("Random result: 1")), Interpreter::interpret(&programs_list[4]).1);
+impl Instruction {
+    // Fixed-width binary form, operands are referenced by their id in `names`. Operand lists are
+    // interned as a single newline separated symbol, since a source line can't contain one
+    pub fn encode(&self, names: &mut SymbolTable) -> [u8; ENCODED_SIZE] {
+        let (condition, instruction) = match self {
+            Instruction::Cond(condition, inner) => {
+                let position = Condition::SUFFIXES.iter().position(|(_, suffix)| suffix == condition).unwrap();
+                (position as u8 + 1, &**inner)
+            }
+            other => (0, other),
+        };
+        let (opcode, operands): (u8, Vec<u32>) = match instruction {
+            // Conditions don't nest, the parser turns a suffixed stem into a Nop as well
+            Instruction::Nop | Instruction::Cond(..) => (0, vec![]),
+            Instruction::Mov(dst, src) => (1, vec![names.id(dst), names.id(src)]),
+            Instruction::Inc(dst) => (2, vec![names.id(dst)]),
+            Instruction::Dec(dst) => (3, vec![names.id(dst)]),
+            Instruction::Add(dst, src) => (4, vec![names.id(dst), names.id(src)]),
+            Instruction::Sub(dst, src) => (5, vec![names.id(dst), names.id(src)]),
+            Instruction::Mul(dst, src) => (6, vec![names.id(dst), names.id(src)]),
+            Instruction::Div(dst, src) => (7, vec![names.id(dst), names.id(src)]),
+            Instruction::Adc(dst, src) => (8, vec![names.id(dst), names.id(src)]),
+            Instruction::Sbb(dst, src) => (9, vec![names.id(dst), names.id(src)]),
+            Instruction::Mulmod(dst, lhs, rhs, modulus) => (10, vec![names.id(dst), names.id(lhs), names.id(rhs), names.id(modulus)]),
+            Instruction::Zero(registers) => (11, vec![names.id(&join(registers, "\n"))]),
+            Instruction::Lahf(dst) => (12, vec![names.id(dst)]),
+            Instruction::Sahf(src) => (13, vec![names.id(src)]),
+            Instruction::Function(label) => (14, vec![names.id(label)]),
+            Instruction::Call(label) => (15, vec![names.id(label)]),
+            Instruction::CallExpr(dst, label) => (16, vec![names.id(dst), names.id(label)]),
+            Instruction::Push(src) => (17, vec![names.id(src)]),
+            Instruction::Cmp(lhs, rhs) => (18, vec![names.id(lhs), names.id(rhs)]),
+            Instruction::Between(value, lo, hi) => (19, vec![names.id(value), names.id(lo), names.id(hi)]),
+            Instruction::Jmp(label) => (20, vec![names.id(label)]),
+            Instruction::Jne(label) => (21, vec![names.id(label)]),
+            Instruction::Je(label) => (22, vec![names.id(label)]),
+            Instruction::Jge(label) => (23, vec![names.id(label)]),
+            Instruction::Jg(label) => (24, vec![names.id(label)]),
+            Instruction::Jle(label) => (25, vec![names.id(label)]),
+            Instruction::Jl(label) => (26, vec![names.id(label)]),
+            Instruction::Jcxz(register, label) => (27, vec![names.id(register), names.id(label)]),
+            Instruction::Read(dst) => (28, vec![names.id(dst)]),
+            Instruction::Msg(args) => (29, vec![names.id(&join(args, "\n"))]),
+            Instruction::Flush => (30, vec![]),
+            Instruction::Ret(count) => (31, vec![*count as u32, (*count >> 32) as u32]),
+            Instruction::End => (32, vec![]),
+            Instruction::Pad(cycles) => (33, vec![*cycles as u32, (*cycles >> 32) as u32]),
+            Instruction::Sleep(duration) => (34, vec![names.id(duration)]),
+            Instruction::Rand(dst) => (35, vec![names.id(dst)]),
+            Instruction::Pop(dst) => (36, vec![names.id(dst)]),
+            Instruction::Abort(args) => (37, vec![names.id(&join(args, "\n"))]),
+            Instruction::And(dst, src) => (38, vec![names.id(dst), names.id(src)]),
+            Instruction::Or(dst, src) => (39, vec![names.id(dst), names.id(src)]),
+            Instruction::Xor(dst, src) => (40, vec![names.id(dst), names.id(src)]),
+            Instruction::Not(dst) => (41, vec![names.id(dst)]),
+            Instruction::Shl(dst, src) => (42, vec![names.id(dst), names.id(src)]),
+            Instruction::Shr(dst, src) => (43, vec![names.id(dst), names.id(src)]),
+            Instruction::Sar(dst, src) => (44, vec![names.id(dst), names.id(src)]),
+            Instruction::Mod(dst, src) => (45, vec![names.id(dst), names.id(src)]),
+            Instruction::Neg(dst) => (46, vec![names.id(dst)]),
+            Instruction::Abs(dst) => (47, vec![names.id(dst)]),
+            Instruction::Xchg(first, second) => (48, vec![names.id(first), names.id(second)]),
+            Instruction::Test(lhs, rhs) => (49, vec![names.id(lhs), names.id(rhs)]),
+            Instruction::Cmpz(value) => (50, vec![names.id(value)]),
+            Instruction::Command(name, args) => (51, vec![names.id(name), names.id(&join(args, "\n"))]),
+            Instruction::MsgErr(args) => (52, vec![names.id(&join(args, "\n"))]),
+            Instruction::CtxSave(bank) => (53, vec![names.id(bank)]),
+            Instruction::CtxLoad(bank) => (54, vec![names.id(bank)]),
+            Instruction::CtxCopyPrefix(bank, prefix) => (55, vec![names.id(bank), names.id(prefix)]),
+        };
+
+        let mut bytes = [0; ENCODED_SIZE];
+        bytes[0] = opcode;
+        bytes[1] = condition;
+        for (i, operand) in operands.iter().enumerate() {
+            bytes[4 + 4 * i..8 + 4 * i].copy_from_slice(&operand.to_le_bytes());
+        }
+        bytes
+    }
+
+    // Inverse of `encode` with the same `names`, None for an unknown opcode or symbol id
+    pub fn decode(bytes: &[u8; ENCODED_SIZE], names: &SymbolTable) -> Option<Instruction> {
+        let word = |i: usize| u32::from_le_bytes(bytes[4 + 4 * i..8 + 4 * i].try_into().unwrap());
+        let symbol = |i: usize| names.get(word(i));
+        let list = |i: usize| {
+            symbol(i).map(|joined| match joined.as_str() {
+                "" => vec![],
+                joined => joined.split('\n').map(Symbol::new).collect(),
+            })
+        };
+        let instruction = match bytes[0] {
+            0 => Instruction::Nop,
+            1 => Instruction::Mov(symbol(0)?, symbol(1)?),
+            2 => Instruction::Inc(symbol(0)?),
+            3 => Instruction::Dec(symbol(0)?),
+            4 => Instruction::Add(symbol(0)?, symbol(1)?),
+            5 => Instruction::Sub(symbol(0)?, symbol(1)?),
+            6 => Instruction::Mul(symbol(0)?, symbol(1)?),
+            7 => Instruction::Div(symbol(0)?, symbol(1)?),
+            8 => Instruction::Adc(symbol(0)?, symbol(1)?),
+            9 => Instruction::Sbb(symbol(0)?, symbol(1)?),
+            10 => Instruction::Mulmod(symbol(0)?, symbol(1)?, symbol(2)?, symbol(3)?),
+            11 => Instruction::Zero(list(0)?),
+            12 => Instruction::Lahf(symbol(0)?),
+            13 => Instruction::Sahf(symbol(0)?),
+            14 => Instruction::Function(symbol(0)?),
+            15 => Instruction::Call(symbol(0)?),
+            16 => Instruction::CallExpr(symbol(0)?, symbol(1)?),
+            17 => Instruction::Push(symbol(0)?),
+            18 => Instruction::Cmp(symbol(0)?, symbol(1)?),
+            19 => Instruction::Between(symbol(0)?, symbol(1)?, symbol(2)?),
+            20 => Instruction::Jmp(symbol(0)?),
+            21 => Instruction::Jne(symbol(0)?),
+            22 => Instruction::Je(symbol(0)?),
+            23 => Instruction::Jge(symbol(0)?),
+            24 => Instruction::Jg(symbol(0)?),
+            25 => Instruction::Jle(symbol(0)?),
+            26 => Instruction::Jl(symbol(0)?),
+            27 => Instruction::Jcxz(symbol(0)?, symbol(1)?),
+            28 => Instruction::Read(symbol(0)?),
+            29 => Instruction::Msg(list(0)?),
+            30 => Instruction::Flush,
+            31 => Instruction::Ret(word(0) as u64 | (word(1) as u64) << 32),
+            32 => Instruction::End,
+            33 => Instruction::Pad(word(0) as u64 | (word(1) as u64) << 32),
+            34 => Instruction::Sleep(symbol(0)?),
+            35 => Instruction::Rand(symbol(0)?),
+            36 => Instruction::Pop(symbol(0)?),
+            37 => Instruction::Abort(list(0)?),
+            38 => Instruction::And(symbol(0)?, symbol(1)?),
+            39 => Instruction::Or(symbol(0)?, symbol(1)?),
+            40 => Instruction::Xor(symbol(0)?, symbol(1)?),
+            41 => Instruction::Not(symbol(0)?),
+            42 => Instruction::Shl(symbol(0)?, symbol(1)?),
+            43 => Instruction::Shr(symbol(0)?, symbol(1)?),
+            44 => Instruction::Sar(symbol(0)?, symbol(1)?),
+            45 => Instruction::Mod(symbol(0)?, symbol(1)?),
+            46 => Instruction::Neg(symbol(0)?),
+            47 => Instruction::Abs(symbol(0)?),
+            48 => Instruction::Xchg(symbol(0)?, symbol(1)?),
+            49 => Instruction::Test(symbol(0)?, symbol(1)?),
+            50 => Instruction::Cmpz(symbol(0)?),
+            51 => Instruction::Command(symbol(0)?, list(1)?),
+            52 => Instruction::MsgErr(list(0)?),
+            53 => Instruction::CtxSave(symbol(0)?),
+            54 => Instruction::CtxLoad(symbol(0)?),
+            55 => Instruction::CtxCopyPrefix(symbol(0)?, symbol(1)?),
+            _ => return None,
+        };
+
+        match bytes[1] {
+            0 => Some(instruction),
+            n => {
+                let &(_, condition) = Condition::SUFFIXES.get(n as usize - 1)?;
+                Some(Instruction::Cond(condition, Box::new(instruction)))
+            }
+        }
+    }
+
+    // Labels and blank lines only mark positions in the program
+    fn is_executable(&self) -> bool {
+        !matches!(self, Instruction::Function(_) | Instruction::Nop)
+    }
+
+    // The instruction without its condition suffix
+    fn unconditional(&self) -> &Instruction {
+        match self {
+            Instruction::Cond(_, inner) => inner,
+            other => other,
+        }
+    }
+
+    // Operands the instruction writes into
+    fn destinations(&self) -> Vec<&str> {
+        match self.unconditional() {
+            Instruction::Mov(dst, _)
+            | Instruction::Inc(dst)
+            | Instruction::Dec(dst)
+            | Instruction::Add(dst, _)
+            | Instruction::Sub(dst, _)
+            | Instruction::Mul(dst, _)
+            | Instruction::Div(dst, _)
+            | Instruction::Mod(dst, _)
+            | Instruction::Adc(dst, _)
+            | Instruction::Sbb(dst, _)
+            | Instruction::And(dst, _)
+            | Instruction::Or(dst, _)
+            | Instruction::Xor(dst, _)
+            | Instruction::Not(dst)
+            | Instruction::Neg(dst)
+            | Instruction::Abs(dst)
+            | Instruction::Shl(dst, _)
+            | Instruction::Shr(dst, _)
+            | Instruction::Sar(dst, _)
+            | Instruction::Mulmod(dst, ..)
+            | Instruction::Lahf(dst)
+            | Instruction::CallExpr(dst, _)
+            | Instruction::Pop(dst)
+            | Instruction::Rand(dst)
+            | Instruction::Read(dst) => vec![dst],
+            Instruction::Command(..) => vec![ACCUMULATOR],
+            Instruction::Xchg(first, second) => vec![first, second],
+            Instruction::Zero(dsts) => dsts.iter().map(|dst| dst.as_str()).collect(),
+            _ => vec![]
+        }
+    }
+
+    // Label a jump or call aims at
+    fn label(&self) -> Option<&Symbol> {
+        match self.unconditional() {
+            Instruction::Call(label)
+            | Instruction::CallExpr(_, label)
+            | Instruction::Jmp(label)
+            | Instruction::Jcxz(_, label) => Some(label),
+            other => other.branch().map(|(_, label)| label),
+        }
+    }
+
+    // Condition and target of a conditional jump
+    fn branch(&self) -> Option<(Condition, &Symbol)> {
+        match self {
+            Instruction::Jne(label) => Some((Condition::Ne, label)),
+            Instruction::Je(label) => Some((Condition::Eq, label)),
+            Instruction::Jge(label) => Some((Condition::Ge, label)),
+            Instruction::Jg(label) => Some((Condition::Gt, label)),
+            Instruction::Jle(label) => Some((Condition::Le, label)),
+            Instruction::Jl(label) => Some((Condition::Lt, label)),
+            _ => None
+        }
+    }
+
+    // Operands and terms of memory operands written as numbers
+    fn numbers(&self) -> Vec<&str> {
+        self.operands().into_iter()
+            .flat_map(|operand| match operand.strip_prefix('[') {
+                Some(address) => address_terms(address.trim_end_matches(']')).collect(),
+                None => vec![operand.as_str()],
+            })
+            .filter(|operand| operand.trim_start_matches('-').starts_with(|c: char| c.is_ascii_digit()))
+            .collect()
+    }
+
+    // Operands naming a register
+    fn registers(&self) -> Vec<&str> {
+        self.operands().into_iter()
+            .flat_map(|operand| match operand.strip_prefix('[') {
+                Some(address) => address_terms(address.trim_end_matches(']'))
+                    .map(|term| term.trim_start_matches('-'))
+                    .collect(),
+                None => vec![operand.as_str()],
+            })
+            .filter(|operand| is_register(operand))
+            .collect()
+    }
+
+    // Operands holding a value, leaving out labels and bank names
+    fn operands(&self) -> Vec<&Symbol> {
+        match self.unconditional() {
+            Instruction::Mov(dst, src)
+            | Instruction::Xchg(dst, src)
+            | Instruction::Add(dst, src)
+            | Instruction::Sub(dst, src)
+            | Instruction::Mul(dst, src)
+            | Instruction::Div(dst, src)
+            | Instruction::Mod(dst, src)
+            | Instruction::Adc(dst, src)
+            | Instruction::Sbb(dst, src)
+            | Instruction::And(dst, src)
+            | Instruction::Or(dst, src)
+            | Instruction::Xor(dst, src)
+            | Instruction::Shl(dst, src)
+            | Instruction::Shr(dst, src)
+            | Instruction::Sar(dst, src)
+            | Instruction::Cmp(dst, src)
+            | Instruction::Test(dst, src) => vec![dst, src],
+            Instruction::Mulmod(dst, lhs, rhs, modulus) => vec![dst, lhs, rhs, modulus],
+            Instruction::Between(value, low, high) => vec![value, low, high],
+            Instruction::Inc(dst)
+            | Instruction::Dec(dst)
+            | Instruction::Not(dst)
+            | Instruction::Neg(dst)
+            | Instruction::Abs(dst)
+            | Instruction::Cmpz(dst)
+            | Instruction::Jcxz(dst, _)
+            | Instruction::Lahf(dst)
+            | Instruction::Sahf(dst)
+            | Instruction::CallExpr(dst, _)
+            | Instruction::Push(dst)
+            | Instruction::Pop(dst)
+            | Instruction::Sleep(dst)
+            | Instruction::Rand(dst)
+            | Instruction::Read(dst) => vec![dst],
+            Instruction::Zero(args)
+            | Instruction::Msg(args)
+            | Instruction::MsgErr(args)
+            | Instruction::Abort(args)
+            | Instruction::Command(_, args) => args.iter().collect(),
+            _ => vec![]
+        }
     }
-}
\ No newline at end of file
+
+    fn cycles(&self) -> u64 {
+        match self {
+            Instruction::Pad(count) => *count,
+            other if other.is_executable() => 1,
+            _ => 0,
+        }
+    }
+
+    // Plain-English description of what the instruction does
+    pub fn describe(&self) -> String {
+        match self {
+            Instruction::Mov(dst, src) if dst == "rip" => format!("jump to instruction {}", src),
+            Instruction::Mov(dst, src) => format!("{} = {}", dst, src),
+            Instruction::Xchg(first, second) => format!("swap {} and {}", first, second),
+            Instruction::Inc(dst) => format!("{} = {} + 1", dst, dst),
+            Instruction::Dec(dst) => format!("{} = {} - 1", dst, dst),
+            Instruction::Add(dst, src) => format!("{} = {} + {}", dst, dst, src),
+            Instruction::Sub(dst, src) => format!("{} = {} - {}", dst, dst, src),
+            Instruction::Mul(dst, src) => format!("{} = {} * {}", dst, dst, src),
+            Instruction::Div(dst, src) => format!("{} = {} / {}", dst, dst, src),
+            Instruction::Mod(dst, src) => format!("{} = {} % {}", dst, dst, src),
+            Instruction::Adc(dst, src) => format!("{} = {} + {} + CF", dst, dst, src),
+            Instruction::Sbb(dst, src) => format!("{} = {} - {} - CF", dst, dst, src),
+            Instruction::And(dst, src) => format!("{} = {} & {}", dst, dst, src),
+            Instruction::Or(dst, src) => format!("{} = {} | {}", dst, dst, src),
+            Instruction::Xor(dst, src) => format!("{} = {} ^ {}", dst, dst, src),
+            Instruction::Not(dst) => format!("{} = !{}", dst, dst),
+            Instruction::Neg(dst) => format!("{} = -{}", dst, dst),
+            Instruction::Abs(dst) => format!("{} = |{}|", dst, dst),
+            Instruction::Shl(dst, src) => format!("{} = {} << {}", dst, dst, src),
+            Instruction::Shr(dst, src) => format!("{} = {} >> {} (filling with zeros)", dst, dst, src),
+            Instruction::Sar(dst, src) => format!("{} = {} >> {} (keeping the sign)", dst, dst, src),
+            Instruction::Mulmod(dst, lhs, rhs, modulus) => format!("{} = ({} * {}) % {}", dst, lhs, rhs, modulus),
+            Instruction::Zero(dsts) => format!("{} = 0", join(dsts, " = ")),
+            Instruction::Lahf(dst) => format!("{} = packed flags", dst),
+            Instruction::Sahf(src) => format!("flags = unpacked {}", src),
+            Instruction::Cond(condition, inner) => format!("if {}: {}", condition.describe(), inner.describe()),
+            Instruction::Function(name) => format!("label {}", name),
+            Instruction::Call(label) => format!("call subroutine {}", label),
+            Instruction::CallExpr(dst, label) => format!("{} = result of subroutine {}", dst, label),
+            Instruction::Push(src) => format!("push {} onto the value stack", src),
+            Instruction::Pop(dst) => format!("{} = value popped from the value stack", dst),
+            Instruction::Cmp(dst, src) => format!("compare {} with {}", dst, src),
+            Instruction::Test(dst, src) => format!("set the flags from {} & {}", dst, src),
+            Instruction::Cmpz(value) => format!("compare {} with 0", value),
+            Instruction::Between(value, low, high) => format!("check that {} is between {} and {}", value, low, high),
+            Instruction::Jmp(label) => format!("jump to {}", label),
+            Instruction::Jne(label) => format!("jump to {} if not equal", label),
+            Instruction::Je(label) => format!("jump to {} if equal", label),
+            Instruction::Jge(label) => format!("jump to {} if greater or equal", label),
+            Instruction::Jg(label) => format!("jump to {} if greater", label),
+            Instruction::Jle(label) => format!("jump to {} if less or equal", label),
+            Instruction::Jl(label) => format!("jump to {} if less", label),
+            Instruction::Jcxz(counter, label) => format!("jump to {} if {} is zero", label, counter),
+            Instruction::Read(dst) => format!("{} = next input value", dst),
+            Instruction::Rand(dst) => format!("{} = random number", dst),
+            Instruction::Msg(args) => format!("output {}", join(args, ", ")),
+            Instruction::Abort(args) => format!("fail with {}", join(args, ", ")),
+            Instruction::MsgErr(args) => format!("output {} on stderr", join(args, ", ")),
+            Instruction::CtxSave(bank) => format!("save the registers into bank {}", bank),
+            Instruction::CtxLoad(bank) => format!("replace the registers with bank {}", bank),
+            Instruction::CtxCopyPrefix(bank, prefix) => format!("copy the registers starting with {} from bank {}", prefix, bank),
+            Instruction::Command(name, args) => format!("run the command {} with {}, a = its result if any", name, join(args, ", ")),
+            Instruction::Flush => String::from("emit buffered messages"),
+            Instruction::Ret(0) => String::from("return to the caller"),
+            Instruction::Ret(count) => format!("return to the caller and drop {} values", count),
+            Instruction::End => String::from("end the program"),
+            Instruction::Pad(count) => format!("do nothing for {} cycles", count),
+            Instruction::Sleep(duration) => format!("wait {} milliseconds", duration),
+            Instruction::Nop => String::new(),
+        }
+    }
+}
+
+// Disassemble back into source form
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Mov(dst, src) => write!(f, "mov {}, {}", dst, src),
+            Instruction::Xchg(first, second) => write!(f, "xchg {}, {}", first, second),
+            Instruction::Inc(dst) => write!(f, "inc {}", dst),
+            Instruction::Dec(dst) => write!(f, "dec {}", dst),
+            Instruction::Add(dst, src) => write!(f, "add {}, {}", dst, src),
+            Instruction::Sub(dst, src) => write!(f, "sub {}, {}", dst, src),
+            Instruction::Mul(dst, src) => write!(f, "mul {}, {}", dst, src),
+            Instruction::Div(dst, src) => write!(f, "div {}, {}", dst, src),
+            Instruction::Mod(dst, src) => write!(f, "mod {}, {}", dst, src),
+            Instruction::Adc(dst, src) => write!(f, "adc {}, {}", dst, src),
+            Instruction::Sbb(dst, src) => write!(f, "sbb {}, {}", dst, src),
+            Instruction::And(dst, src) => write!(f, "and {}, {}", dst, src),
+            Instruction::Or(dst, src) => write!(f, "or {}, {}", dst, src),
+            Instruction::Xor(dst, src) => write!(f, "xor {}, {}", dst, src),
+            Instruction::Not(dst) => write!(f, "not {}", dst),
+            Instruction::Neg(dst) => write!(f, "neg {}", dst),
+            Instruction::Abs(dst) => write!(f, "abs {}", dst),
+            Instruction::Shl(dst, src) => write!(f, "shl {}, {}", dst, src),
+            Instruction::Shr(dst, src) => write!(f, "shr {}, {}", dst, src),
+            Instruction::Sar(dst, src) => write!(f, "sar {}, {}", dst, src),
+            Instruction::Mulmod(dst, lhs, rhs, modulus) => write!(f, "mulmod {}, {}, {}, {}", dst, lhs, rhs, modulus),
+            Instruction::Zero(dsts) => write!(f, "zero {}", join(dsts, ", ")),
+            Instruction::Lahf(dst) => write!(f, "lahf {}", dst),
+            Instruction::Sahf(src) => write!(f, "sahf {}", src),
+            Instruction::Cond(condition, inner) => match inner.to_string().split_once(' ') {
+                Some((mnemonic, operands)) => write!(f, "{}{} {}", mnemonic, condition, operands),
+                None => write!(f, "{}{}", inner, condition),
+            },
+            Instruction::Function(name) => write!(f, "{}:", name),
+            Instruction::Call(label) => write!(f, "call {}", label),
+            Instruction::CallExpr(dst, label) => write!(f, "call_expr {}, {}", dst, label),
+            Instruction::Push(src) => write!(f, "push {}", src),
+            Instruction::Pop(dst) => write!(f, "pop {}", dst),
+            Instruction::Cmp(dst, src) => write!(f, "cmp {}, {}", dst, src),
+            Instruction::Test(dst, src) => write!(f, "test {}, {}", dst, src),
+            Instruction::Cmpz(value) => write!(f, "cmpz {}", value),
+            Instruction::Between(value, low, high) => write!(f, "between {}, {}, {}", value, low, high),
+            Instruction::Jmp(label) => write!(f, "jmp {}", label),
+            Instruction::Jne(label) => write!(f, "jne {}", label),
+            Instruction::Je(label) => write!(f, "je {}", label),
+            Instruction::Jge(label) => write!(f, "jge {}", label),
+            Instruction::Jg(label) => write!(f, "jg {}", label),
+            Instruction::Jle(label) => write!(f, "jle {}", label),
+            Instruction::Jl(label) => write!(f, "jl {}", label),
+            Instruction::Jcxz(counter, label) => write!(f, "jcxz {}, {}", counter, label),
+            Instruction::Read(dst) => write!(f, "read {}", dst),
+            Instruction::Rand(dst) => write!(f, "rand {}", dst),
+            Instruction::Sleep(duration) => write!(f, "sleep {}", duration),
+            Instruction::Msg(args) => write!(f, "msg {}", join(args, ", ")),
+            Instruction::Abort(args) => write!(f, "abort {}", join(args, ", ")),
+            Instruction::MsgErr(args) => write!(f, "msgerr {}", join(args, ", ")),
+            Instruction::CtxSave(bank) => write!(f, "ctxsave {}", bank),
+            Instruction::CtxLoad(bank) => write!(f, "ctxload {}", bank),
+            Instruction::CtxCopyPrefix(bank, prefix) => write!(f, "ctxcopy_prefix {}, {}", bank, prefix),
+            Instruction::Command(name, args) if args.is_empty() => write!(f, "{}", name),
+            Instruction::Command(name, args) => write!(f, "{} {}", name, join(args, ", ")),
+            Instruction::Flush => write!(f, "flush"),
+            Instruction::Ret(0) => write!(f, "ret"),
+            Instruction::Ret(count) => write!(f, "ret {}", count),
+            Instruction::End => write!(f, "end"),
+            Instruction::Pad(count) => write!(f, "nop {}", count),
+            Instruction::Nop => Ok(()),
+        }
+    }
+}
+
+// Parse on its own, with names that aren't shared with any program
+impl From<String> for Instruction {
+    fn from(raw_instruction: String) -> Self {
+        Instruction::parse(&raw_instruction, &mut SymbolTable::default())
+    }
+}
+
+impl Instruction {
+    // Parse a cleaned line, interning its operands into `names`
+    fn parse(raw_instruction: &str, names: &mut SymbolTable) -> Self {
+        if raw_instruction.is_empty() {
+            return Instruction::Nop;
+        }
+
+        let (mnemonic, params) = tokenize(raw_instruction, names);
+        // Mnemonics are matched case-insensitively, labels and operands keep their case
+        let lowercase = mnemonic.to_ascii_lowercase();
+        // Missing operands are reported by `Program::parse`
+        let found = written_operands(&params);
+        if operand_counts(&lowercase).is_some_and(|expected| found.is_none_or(|found| found < *expected.start())) {
+            return Instruction::Nop;
+        }
+
+        match lowercase.as_str() {
+            "mov" => Instruction::Mov(params[0].clone(), params[1].clone()),
+            "xchg" => Instruction::Xchg(params[0].clone(), params[1].clone()),
+            "inc" => Instruction::Inc(params[0].clone()),
+            "dec" => Instruction::Dec(params[0].clone()),
+            "add" => Instruction::Add(params[0].clone(), params[1].clone()),
+            "sub" => Instruction::Sub(params[0].clone(), params[1].clone()),
+            "mul" if params.len() == 1 => Instruction::Mul(names.intern(ACCUMULATOR), params[0].clone()),
+            "mul" => Instruction::Mul(params[0].clone(), params[1].clone()),
+            "div" if params.len() == 1 => Instruction::Div(names.intern(ACCUMULATOR), params[0].clone()),
+            "div" => Instruction::Div(params[0].clone(), params[1].clone()),
+            "mod" => Instruction::Mod(params[0].clone(), params[1].clone()),
+            "adc" => Instruction::Adc(params[0].clone(), params[1].clone()),
+            "sbb" => Instruction::Sbb(params[0].clone(), params[1].clone()),
+            "and" => Instruction::And(params[0].clone(), params[1].clone()),
+            "or" => Instruction::Or(params[0].clone(), params[1].clone()),
+            "xor" => Instruction::Xor(params[0].clone(), params[1].clone()),
+            "not" => Instruction::Not(params[0].clone()),
+            "neg" => Instruction::Neg(params[0].clone()),
+            "abs" => Instruction::Abs(params[0].clone()),
+            "shl" => Instruction::Shl(params[0].clone(), params[1].clone()),
+            "shr" => Instruction::Shr(params[0].clone(), params[1].clone()),
+            "sar" => Instruction::Sar(params[0].clone(), params[1].clone()),
+            "mulmod" => Instruction::Mulmod(
+                params[0].clone(),
+                params[1].clone(),
+                params[2].clone(),
+                params[3].clone(),
+            ),
+            "zero" => Instruction::Zero(params.clone()),
+            "lahf" => Instruction::Lahf(params[0].clone()),
+            "sahf" => Instruction::Sahf(params[0].clone()),
+            "call" => Instruction::Call(params[0].clone()),
+            "call_expr" => Instruction::CallExpr(params[0].clone(), params[1].clone()),
+            "push" => Instruction::Push(params[0].clone()),
+            "pop" => Instruction::Pop(params[0].clone()),
+            "cmp" => Instruction::Cmp(params[0].clone(), params[1].clone()),
+            "test" => Instruction::Test(params[0].clone(), params[1].clone()),
+            "cmpz" => Instruction::Cmpz(params[0].clone()),
+            "between" => Instruction::Between(params[0].clone(), params[1].clone(), params[2].clone()),
+            "jmp" => Instruction::Jmp(params[0].clone()),
+            // `jz` and `jnz` read the same flag, which arithmetic sets too once a register width is given
+            "jne" | "jnz" => Instruction::Jne(params[0].clone()),
+            "je" | "jz" => Instruction::Je(params[0].clone()),
+            "jge" => Instruction::Jge(params[0].clone()),
+            "jg" => Instruction::Jg(params[0].clone()),
+            "jle" => Instruction::Jle(params[0].clone()),
+            "jl" => Instruction::Jl(params[0].clone()),
+            "jcxz" => Instruction::Jcxz(params[0].clone(), params[1].clone()),
+            "read" | "in" => Instruction::Read(params[0].clone()),
+            "rand" => Instruction::Rand(params[0].clone()),
+            "sleep" => Instruction::Sleep(params[0].clone()),
+            "msg" => Instruction::Msg(params.clone()),
+            "abort" => Instruction::Abort(params.clone()),
+            "msgerr" => Instruction::MsgErr(params.clone()),
+            "ctxsave" => Instruction::CtxSave(params[0].clone()),
+            "ctxload" => Instruction::CtxLoad(params[0].clone()),
+            "ctxcopy_prefix" => Instruction::CtxCopyPrefix(params[0].clone(), params[1].clone()),
+            "flush" => Instruction::Flush,
+            "ret" => Instruction::Ret(parse_count(&params[0]).unwrap_or(0)),
+            "end" => Instruction::End,
+            "nop" => Instruction::Pad(parse_count(&params[0]).unwrap_or(1)),
+            _ if mnemonic.ends_with(":") => Instruction::Function(names.intern(mnemonic.trim_matches(':'))),
+            other => match Condition::split(other) {
+                Some((stem, condition)) => match Instruction::parse(&raw_instruction.replacen(mnemonic, stem, 1), names) {
+                    Instruction::Function(_) | Instruction::Cond(..) | Instruction::Nop => Instruction::Nop,
+                    inner => Instruction::Cond(condition, Box::new(inner)),
+                },
+                None => Instruction::Nop
+            }
+        }
+    }
+}
+
+fn is_register(operand: &str) -> bool {
+    operand != "rip"
+        && operand.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && operand.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Strip whitespace inside operands like `[ a + 2 ]` or `- 5`, leaving quoted text untouched
+fn normalize_operand(raw: &str) -> String {
+    let operand = raw.trim();
+    if operand.contains('\'') {
+        operand.to_string()
+    } else {
+        operand.split_whitespace().collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Termination {
+    // `end` was reached, carrying the stored output
+    End(String),
+    // Execution ran past the last instruction without reaching `end`
+    FellThrough,
+    // Execution left the line range given to `with_line_range`, carrying the output so far
+    LeftRange(String),
+    // The stop flag given to `with_stop_flag` was raised, carrying the output so far
+    Interrupted(String),
+    // Paused before the instruction with this index, the next `run` continues from there
+    Breakpoint(usize),
+    // `abort` was reached, carrying its message
+    Aborted(String),
+}
+
+impl Termination {
+    // Output in the shape returned by `Interpreter::interpret`
+    pub fn into_output(self) -> Option<String> {
+        match self {
+            Termination::End(out) | Termination::LeftRange(out) | Termination::Interrupted(out) => Some(out),
+            Termination::FellThrough | Termination::Breakpoint(_) | Termination::Aborted(_) => None,
+        }
+    }
+}
+
+// How results that have no value are handled: dividing by zero, `i64::MIN` by -1, `neg` and `abs` of
+// `i64::MIN` and shift counts outside 0 to 63. Arithmetic that doesn't fit is up to `ArithmeticMode`,
+// except under `Checked`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StrictnessPolicy {
+    // Nothing fails: dividing by zero gives 0 and shifting by 64 or more shifts every bit out
+    Lenient,
+    // Like a CPU: dividing by zero or `i64::MIN` by -1 is an error, shift counts are masked
+    #[default]
+    Hardware,
+    // Like Rust debug builds but as errors: shift counts outside 0 to 63 and arithmetic that doesn't
+    // fit fail too, whatever the arithmetic mode
+    Checked,
+}
+
+// How `div` rounds quotients that aren't whole
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rounding {
+    // Like Rust and C, `-7 / 2` is `-3`
+    #[default]
+    TruncTowardZero,
+    // Like Python, `-7 / 2` is `-4`
+    FloorDivision,
+}
+
+// What inc, dec, add, sub and mul do with results that don't fit the register, unless the strictness
+// policy is `Checked`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    // Keep the low bits, `i64::MAX + 1` is `i64::MIN`
+    #[default]
+    Wrapping,
+    // Stop at the largest or smallest value the register holds, 0 to 2^bits - 1 for a narrower one
+    Saturating,
+    // Fail with an overflow error
+    Checked,
+}
+
+impl Rounding {
+    fn divide(self, lhs: i64, rhs: i64) -> i64 {
+        let quotient = lhs / rhs;
+        match self {
+            Rounding::FloorDivision if lhs % rhs != 0 && (lhs < 0) != (rhs < 0) => quotient - 1,
+            _ => quotient,
+        }
+    }
+}
+
+// Order of the 8 bytes a memory access spans
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    // Least significant byte at the lowest address, like x86
+    #[default]
+    Little,
+    // Most significant byte at the lowest address, like network byte order
+    Big,
+}
+
+impl Endianness {
+    fn store(self, value: i64) -> [u8; 8] {
+        match self {
+            Endianness::Little => value.to_le_bytes(),
+            Endianness::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn load(self, bytes: [u8; 8]) -> i64 {
+        match self {
+            Endianness::Little => i64::from_le_bytes(bytes),
+            Endianness::Big => i64::from_be_bytes(bytes),
+        }
+    }
+}
+
+// Register width given to `with_width`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Bits8,
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+impl Width {
+    // The width with this many bits, None unless 8, 16, 32 or 64
+    pub fn from_bits(bits: u32) -> Option<Width> {
+        match bits {
+            8 => Some(Width::Bits8),
+            16 => Some(Width::Bits16),
+            32 => Some(Width::Bits32),
+            64 => Some(Width::Bits64),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Width::Bits8 => 8,
+            Width::Bits16 => 16,
+            Width::Bits32 => 32,
+            Width::Bits64 => 64,
+        }
+    }
+}
+
+// Flags set by `cmp` and friends, each 0 or 1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub zf: u8,
+    pub cf: u8,
+    pub sf: u8,
+    pub of: u8,
+    pub pf: u8,
+}
+
+// Time and space used by a run so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub steps: u64,
+    pub cycles: u64,
+    pub peak_stack_depth: usize,
+    // Registers and memory are never freed, so their counts are also their peaks
+    pub registers: usize,
+    pub memory_bytes: usize,
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Steps: {}", self.steps)?;
+        writeln!(f, "Cycles: {}", self.cycles)?;
+        writeln!(f, "Peak stack depth: {}", self.peak_stack_depth)?;
+        writeln!(f, "Registers used: {}", self.registers)?;
+        writeln!(f, "Memory bytes used: {}", self.memory_bytes)
+    }
+}
+
+// What a single `Interpreter::step` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    // Index of the executed instruction
+    pub index: usize,
+    // Registers and memory cells written, in order, with their new values
+    pub writes: Vec<(String, i64)>,
+    // The flags packed like `lahf` does, when the instruction changed them
+    pub flags: Option<u8>,
+    // Set once the program stopped
+    pub termination: Option<Termination>,
+    // Number of the step among everything the run emitted, see `Interpreter::events`
+    pub event: u64,
+}
+
+pub struct Interpreter<'a> {
+    // Return addresses
+    stack: Vec<usize>,
+    // Destination of every active call, parallel to `stack`, set for `call_expr`
+    results: Vec<Option<Symbol>>,
+    // Values pushed with `push`
+    values: Vec<i64>,
+    register: SymbolMap<i64>,
+    // Register sets saved with `ctxsave`
+    banks: SymbolMap<SymbolMap<i64>>,
+    rip: usize,
+    zf: u8,
+    cf: u8,
+    // Sign, signed overflow and parity of the low byte of the last `cmp` or `test` result
+    sf: u8,
+    of: u8,
+    pf: u8,
+    out: String,
+    memory: BTreeMap<i64, u8>,
+    input: VecDeque<i64>,
+    // State of the generator behind `rand`, never zero
+    random: u64,
+    steps: u64,
+    // Emitted messages and steps, see `events`
+    events: u64,
+    cycles: u64,
+    // Milliseconds slept by `sleep`
+    clock: u64,
+    // Whether `sleep` really waits instead of only advancing `clock`
+    real_time: bool,
+    // Deepest the call stack got
+    peak_stack: usize,
+    profile: Vec<u64>,
+    max_registers: Option<usize>,
+    // Executed instructions a run may take before it fails
+    max_steps: Option<u64>,
+    max_stack_depth: usize,
+    breakpoints: BTreeSet<usize>,
+    // Breakpoint the last run stopped at, so resuming doesn't stop there again
+    paused_at: Option<usize>,
+    width: Option<Width>,
+    rounding: Rounding,
+    arithmetic: ArithmeticMode,
+    strictness: StrictnessPolicy,
+    endianness: Endianness,
+    last_line: Option<usize>,
+    sink: Option<Box<dyn OutputSink + 'a>>,
+    stop: Option<&'a AtomicBool>,
+    // Writes recorded for the current `step`
+    writes: Option<Vec<(String, i64)>>,
+    pub program: Rc<Program<'a>>,
+    // Handler of every instruction that has one, by index
+    prepared: Rc<Vec<Option<Prepared<'a>>>>,
+}
+
+impl Display for Interpreter<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let delimiter = "-".repeat(20);
+
+        // Registers
+        writeln!(f, "Registers:")?;
+        writeln!(f, "{}", delimiter)?;
+        for (key, value) in self.register.iter() {
+            writeln!(f, "{:<5}: {:<10}", key, value)?;
+        }
+        writeln!(f, "{}", delimiter)?;
+
+        // Stack
+        write!(f, "\nStack:")?;
+        if !self.stack.is_empty() {
+            writeln!(f, "{}", delimiter)?;
+            for (num, line) in self.stack.iter().enumerate() {
+                writeln!(f, "{:<10}: {:<10}", num, line)?;
+            }
+            writeln!(f, "{}", delimiter)?;
+        } else {
+            writeln!(f, "Empty")?;
+        }
+
+        // Flags
+        write!(f, "\nFlags:")?;
+        write!(f, "\n{}\n", delimiter)?;
+        writeln!(f, "{:<2}: {:<10}", "ZF", self.zf)?;
+        writeln!(f, "{:<2}: {:<10}", "CF", self.cf)?;
+        writeln!(f, "{:<2}: {:<10}", "SF", self.sf)?;
+        writeln!(f, "{:<2}: {:<10}", "OF", self.of)?;
+        write!(f, "{:<2}: {:<10}", "PF", self.pf)?;
+        write!(f, "\n{}\n", delimiter)?;
+
+        // Value stack, top last
+        if !self.values.is_empty() {
+            write!(f, "\nValues:")?;
+            write!(f, "\n{}\n", delimiter)?;
+            for (num, value) in self.values.iter().enumerate() {
+                writeln!(f, "{:<10}: {:<10}", num, value)?;
+            }
+            writeln!(f, "{}", delimiter)?;
+        }
+
+        // Memory, 8 bytes per row
+        if !self.memory.is_empty() {
+            write!(f, "\nMemory:")?;
+            write!(f, "\n{}\n", delimiter)?;
+            let rows: BTreeSet<i64> = self.memory.keys().map(|address| address - address % 8).collect();
+            for row in rows {
+                let bytes: Vec<String> = (row..row + 8)
+                    .map(|address| format!("{:02x}", self.memory.get(&address).unwrap_or(&0)))
+                    .collect();
+                writeln!(f, "{:<10}: {}", row, bytes.join(" "))?;
+            }
+            writeln!(f, "{}", delimiter)?;
+        }
+
+        // Output
+        write!(f, "\nOutput: {}\n", self.out)?;
+
+        // Pointer
+        write!(f, "\nRIP: {}\n", self.rip)?;
+
+        // Counters
+        write!(f, "\nSteps: {}\n", self.steps)?;
+        writeln!(f, "Cycles: {}", self.cycles)?;
+        Ok(())
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self::from_program(Program::new(source))
+    }
+
+    // Parse and run a program configured beforehand, like `Program::new(source).without_prelude()`
+    pub fn from_program(mut program: Program<'a>) -> Self {
+        program.parse();
+        let prepared = (0..program.instructions.len()).map(|index| prepare(&program, index)).collect();
+
+        Self {
+            stack: Vec::new(),
+            results: Vec::new(),
+            values: Vec::new(),
+            register: SymbolMap::default(),
+            banks: SymbolMap::default(),
+            rip: 0,
+            zf: 0,
+            cf: 0,
+            sf: 0,
+            of: 0,
+            pf: 0,
+            out: String::new(),
+            memory: BTreeMap::new(),
+            input: VecDeque::new(),
+            random: seed_state(fresh_seed()),
+            steps: 0,
+            events: 0,
+            cycles: 0,
+            clock: 0,
+            real_time: false,
+            peak_stack: 0,
+            profile: vec![0; program.instructions.len()],
+            max_registers: None,
+            max_steps: None,
+            max_stack_depth: MAX_STACK_DEPTH,
+            breakpoints: BTreeSet::new(),
+            paused_at: None,
+            width: None,
+            rounding: Rounding::default(),
+            arithmetic: ArithmeticMode::default(),
+            strictness: StrictnessPolicy::default(),
+            endianness: Endianness::default(),
+            last_line: None,
+            sink: None,
+            stop: None,
+            writes: None,
+            prepared: Rc::new(prepared),
+            program: Rc::new(program),
+        }
+    }
+
+    // Snapshot of the run so far, `continue_from` picks it up again
+    pub fn state(&self) -> State {
+        State {
+            fingerprint: state::fingerprint(self.program.source),
+            rip: self.rip,
+            stack: self.stack.clone(),
+            results: self.results.iter().map(|result| result.as_ref().map(|dst| dst.to_string())).collect(),
+            values: self.values.clone(),
+            registers: self.register.iter().map(|(name, &value)| (name.to_string(), value)).collect(),
+            banks: self.banks.iter()
+                .map(|(bank, registers)| (bank.to_string(), registers.iter().map(|(name, &value)| (name.to_string(), value)).collect()))
+                .collect(),
+            zf: self.zf,
+            cf: self.cf,
+            sf: self.sf,
+            of: self.of,
+            pf: self.pf,
+            memory: self.memory.clone(),
+            input: self.input.iter().copied().collect(),
+            out: self.out.clone(),
+            steps: self.steps,
+            events: self.events,
+            cycles: self.cycles,
+            clock: self.clock,
+            random: self.random,
+            peak_stack: self.peak_stack,
+            profile: self.profile.clone(),
+        }
+    }
+
+    // Resume a run saved with `state`. The program must have the same source as the one the state
+    // was taken from, options like `with_width` have to be applied again
+    pub fn continue_from(program: Program<'a>, state: State) -> Result<Self, InterpretError> {
+        let mut interpreter = Self::from_program(program);
+        if state::fingerprint(interpreter.program.source) != state.fingerprint || state.profile.len() != interpreter.profile.len() {
+            return Err(InterpretError::StateMismatch);
+        }
+
+        interpreter.rip = state.rip;
+        interpreter.stack = state.stack;
+        interpreter.results = state.results.iter().map(|result| result.as_deref().map(Symbol::from)).collect();
+        interpreter.values = state.values;
+        interpreter.register = state.registers.iter().map(|(name, &value)| (Symbol::from(name.as_str()), value)).collect();
+        interpreter.banks = state.banks.iter()
+            .map(|(bank, registers)| {
+                let registers = registers.iter().map(|(name, &value)| (Symbol::from(name.as_str()), value)).collect();
+                (Symbol::from(bank.as_str()), registers)
+            })
+            .collect();
+        interpreter.zf = state.zf;
+        interpreter.cf = state.cf;
+        interpreter.sf = state.sf;
+        interpreter.of = state.of;
+        interpreter.pf = state.pf;
+        interpreter.memory = state.memory;
+        interpreter.input = state.input.into();
+        interpreter.out = state.out;
+        interpreter.steps = state.steps;
+        interpreter.events = state.events;
+        interpreter.cycles = state.cycles;
+        interpreter.clock = state.clock;
+        interpreter.random = state.random;
+        interpreter.peak_stack = state.peak_stack;
+        interpreter.profile = state.profile;
+        Ok(interpreter)
+    }
+
+    pub fn with_max_registers(mut self, limit: usize) -> Self {
+        self.max_registers = Some(limit);
+        self
+    }
+
+    pub fn with_max_steps(mut self, limit: u64) -> Self {
+        self.max_steps = Some(limit);
+        self
+    }
+
+    pub fn with_max_stack_depth(mut self, depth: usize) -> Self {
+        self.max_stack_depth = depth;
+        self
+    }
+
+    pub fn with_strictness(mut self, strictness: StrictnessPolicy) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    pub fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_arithmetic(mut self, mode: ArithmeticMode) -> Self {
+        self.arithmetic = mode;
+        self
+    }
+
+    // Make registers `width` wide. Narrower registers than 64 bits hold unsigned values, every write
+    // is truncated to them and arithmetic sets ZF and CF from its result
+    pub fn with_width(mut self, width: Width) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    // Make `rand` produce the same numbers on every run. Without a seed every interpreter gets its own
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random = seed_state(seed);
+        self
+    }
+
+    // Values consumed in order by `read`
+    pub fn with_input(mut self, values: &[i64]) -> Self {
+        self.input.extend(values);
+        self
+    }
+
+    // Only execute instructions from source line `from` up to line `to`
+    pub fn with_line_range(mut self, from: usize, to: usize) -> Self {
+        self.rip = self.program.index_at_line(from);
+        self.last_line = Some(to);
+        self
+    }
+
+    // Every `msg` is forwarded to the sink as soon as it runs
+    pub fn with_sink(mut self, sink: impl OutputSink + 'a) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    // Make `sleep` block the thread. Otherwise it only advances the simulated clock
+    pub fn with_real_time(mut self) -> Self {
+        self.real_time = true;
+        self
+    }
+
+    // Execution stops before the next instruction once `stop` is set, e.g. from a signal handler
+    pub fn with_stop_flag(mut self, stop: &'a AtomicBool) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    pub fn interpret(input: &'a str) -> (Self, Result<Option<String>, InterpretError>) {
+        let mut interpreter = Interpreter::new(input);
+        let output = interpreter.run().map(Termination::into_output);
+        (interpreter, output)
+    }
+
+    // Like `interpret`, but failing once the program executed `max_steps` instructions
+    pub fn interpret_with_limit(input: &'a str, max_steps: u64) -> (Self, Result<Option<String>, InterpretError>) {
+        let mut interpreter = Interpreter::new(input).with_max_steps(max_steps);
+        let output = interpreter.run().map(Termination::into_output);
+        (interpreter, output)
+    }
+
+    pub fn run_with_input(input: &'a str, values: &[i64]) -> (Self, Result<Option<String>, InterpretError>) {
+        let mut interpreter = Interpreter::new(input).with_input(values);
+        let output = interpreter.run().map(Termination::into_output);
+        (interpreter, output)
+    }
+
+    pub fn run(&mut self) -> Result<Termination, InterpretError> {
+        loop {
+            let result = self.execute();
+            // Numbered like the steps `step` reports, so runs paused in between stay the same
+            if result.is_ok() {
+                self.next_event();
+            }
+            match result {
+                Ok(None) => {}
+                result => return self.finish_output(result).map(|termination| termination.unwrap()),
+            }
+        }
+    }
+
+    // Execute a single instruction and report the registers and memory it wrote
+    pub fn step(&mut self) -> Result<Step, InterpretError> {
+        let index = self.rip;
+        let before = self.packed_flags() as u8;
+        self.writes = Some(Vec::new());
+        let result = self.execute();
+        let result = self.finish_output(result);
+        let writes = self.writes.take().unwrap_or_default();
+        let flags = Some(self.packed_flags() as u8).filter(|&flags| flags != before);
+        let termination = result?;
+        Ok(Step { index, writes, flags, termination, event: self.next_event() })
+    }
+
+    // Tell the sink no more messages follow, unless the run only paused at a breakpoint
+    fn finish_output(&mut self, result: Result<Option<Termination>, InterpretError>) -> Result<Option<Termination>, InterpretError> {
+        if !matches!(result, Ok(None) | Ok(Some(Termination::Breakpoint(_)))) {
+            if let Some(sink) = self.sink.as_mut() {
+                sink.finish();
+            }
+        }
+        result
+    }
+
+    // Checks made before the instruction at `rip` runs, which is counted once it may. Returns how
+    // the run ends instead when it doesn't
+    fn admit(&mut self, program: &Program) -> Result<Option<Termination>, InterpretError> {
+        if self.stop.is_some_and(|stop| stop.load(Ordering::Relaxed)) {
+            return Ok(Some(Termination::Interrupted(self.out.to_owned())));
+        }
+        // Running into the built-in subroutines counts as running past the end
+        let instruction = match program.instructions.get(self.rip) {
+            Some(_) if program.prelude_start == Some(self.rip) => return Ok(Some(Termination::FellThrough)),
+            Some(instruction) => instruction,
+            None => return Ok(Some(Termination::FellThrough)),
+        };
+        if self.last_line.is_some_and(|last_line| program.lines[self.rip] > last_line) {
+            return Ok(Some(Termination::LeftRange(self.out.to_owned())));
+        }
+        if self.paused_at.take() != Some(self.rip) && self.breakpoints.contains(&self.rip) {
+            self.paused_at = Some(self.rip);
+            return Ok(Some(Termination::Breakpoint(self.rip)));
+        }
+        if self.max_steps.is_some_and(|limit| self.steps >= limit) && instruction.is_executable() {
+            return Err(InterpretError::StepLimitExceeded(self.steps));
+        }
+        self.steps += instruction.is_executable() as u64;
+        self.cycles += instruction.cycles();
+        self.profile[self.rip] += 1;
+        Ok(None)
+    }
+
+    // Execute the instruction at `rip`, returning how the run ended once it did
+    fn execute(&mut self) -> Result<Option<Termination>, InterpretError> {
+        let program = Rc::clone(&self.program);
+
+        if let Some(error) = program.errors.first() {
+            return Err(error.clone());
+        }
+        if let Some(termination) = self.admit(&program)? {
+            return Ok(Some(termination));
+        }
+        if let Some(prepared) = &Rc::clone(&self.prepared)[self.rip] {
+            prepared(self)?;
+            return Ok(None);
+        }
+        let instruction = &program.instructions[self.rip];
+
+        // A conditional instruction runs as its inner instruction, or is skipped below
+        let instruction = match instruction {
+            Instruction::Cond(condition, inner) if self.holds(*condition) => inner,
+            other => other,
+        };
+
+        match instruction {
+            Instruction::Mov(dst, src) if dst == "rip" => {
+                // Labels and blank lines are no instructions to land on
+                let target = self.constant_or_register(src)?;
+                let executable = usize::try_from(target).ok()
+                    .and_then(|target| program.instructions.get(target))
+                    .is_some_and(Instruction::is_executable);
+                if !executable {
+                    return Err(InterpretError::InvalidJumpTarget(target));
+                }
+                self.rip = target as usize;
+            }
+
+            // Moving an operand onto itself changes nothing, so it isn't reported as a write either
+            Instruction::Mov(dst, src) if dst == src => {
+                self.rip += 1;
+            }
+
+            Instruction::Mov(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                self.write(dst, src_value)?;
+                self.rip += 1;
+            }
+
+            // A register that doesn't exist yet reads as 0 and is created by the write
+            Instruction::Xchg(first, second) => {
+                let first_value = self.constant_or_register(first)?;
+                let second_value = self.constant_or_register(second)?;
+                self.write(first, second_value)?;
+                self.write(second, first_value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Inc(dst) => {
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.arithmetic(dst, i128::from(dst_value) + 1)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Dec(dst) => {
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.arithmetic(dst, i128::from(dst_value) - 1)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Add(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.arithmetic(dst, i128::from(dst_value) + i128::from(src_value))?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Sub(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.arithmetic(dst, i128::from(dst_value) - i128::from(src_value))?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Mul(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.arithmetic(dst, i128::from(dst_value) * i128::from(src_value))?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Div(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.divide(dst, dst_value, src_value)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Mod(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.remainder(dst, dst_value, src_value)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Adc(dst, src) => {
+                let src_value = self.constant_or_register(src)? as u64;
+                let dst_value = self.constant_or_register(dst)? as u64;
+                let (sum, first) = dst_value.overflowing_add(src_value);
+                let (sum, second) = sum.overflowing_add(self.cf as u64);
+                self.write(dst, sum as i64)?;
+                self.cf = (first || second) as u8;
+                self.rip += 1;
+            }
+
+            Instruction::Sbb(dst, src) => {
+                let src_value = self.constant_or_register(src)? as u64;
+                let dst_value = self.constant_or_register(dst)? as u64;
+                let (difference, first) = dst_value.overflowing_sub(src_value);
+                let (difference, second) = difference.overflowing_sub(self.cf as u64);
+                self.write(dst, difference as i64)?;
+                self.cf = (first || second) as u8;
+                self.rip += 1;
+            }
+
+            Instruction::And(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                self.write(dst, dst_value & src_value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Or(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                self.write(dst, dst_value | src_value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Xor(dst, src) => {
+                let src_value = self.constant_or_register(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                self.write(dst, dst_value ^ src_value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Not(dst) => {
+                let dst_value = self.constant_or_register(dst)?;
+                self.write(dst, !dst_value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Neg(dst) => {
+                let dst_value = self.constant_or_register(dst)?;
+                let value = self.negate(dst, dst_value)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Abs(dst) => {
+                let dst_value = self.constant_or_register(dst)?;
+                let value = if dst_value < 0 { self.negate(dst, dst_value)? } else { dst_value };
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Shl(dst, src) => {
+                let count = self.shift_count(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                self.write(dst, count.map_or(0, |count| dst_value << count))?;
+                self.rip += 1;
+            }
+
+            Instruction::Shr(dst, src) => {
+                let count = self.shift_count(src)?;
+                let dst_value = self.constant_or_register(dst)? as u64;
+                self.write(dst, count.map_or(0, |count| dst_value >> count) as i64)?;
+                self.rip += 1;
+            }
+
+            Instruction::Sar(dst, src) => {
+                let count = self.shift_count(src)?;
+                let dst_value = self.constant_or_register(dst)?;
+                self.write(dst, dst_value >> count.unwrap_or(63))?;
+                self.rip += 1;
+            }
+
+            Instruction::Mulmod(dst, lhs, rhs, modulus) => {
+                let lhs_value = self.constant_or_register(lhs)? as i128;
+                let rhs_value = self.constant_or_register(rhs)? as i128;
+                let modulus_value = self.constant_or_register(modulus)? as i128;
+                // The remainder is smaller than the modulus, so it always fits back into i64
+                let value = match modulus_value {
+                    0 if self.strictness == StrictnessPolicy::Lenient => 0,
+                    0 => return Err(InterpretError::DivisionByZero { register: dst.to_string(), line: program.lines[self.rip] }),
+                    _ => (lhs_value * rhs_value % modulus_value) as i64,
+                };
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Lahf(dst) => {
+                self.write(dst, self.packed_flags())?;
+                self.rip += 1;
+            }
+
+            Instruction::Sahf(src) => {
+                let flags = self.constant_or_register(src)?;
+                self.set_flags(flags);
+                self.rip += 1;
+            }
+
+            Instruction::Zero(dsts) => {
+                for dst in dsts {
+                    self.write(dst, 0)?;
+                }
+                self.rip += 1;
+            }
+
+            Instruction::Call(label) => {
+                self.enter(None)?;
+                self.rip = program.target(label)?;
+            }
+
+            Instruction::CallExpr(dst, label) => {
+                self.enter(Some(dst.clone()))?;
+                self.rip = program.target(label)?;
+            }
+
+            Instruction::Push(src) => {
+                let value = self.constant_or_register(src)?;
+                self.values.push(value);
+                self.rip += 1;
+            }
+
+            Instruction::Pop(dst) => {
+                let value = self.values.pop().ok_or(InterpretError::EmptyValueStack)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Sleep(duration) => {
+                let milliseconds = self.constant_or_register(duration)?.max(0) as u64;
+                self.clock = self.clock.saturating_add(milliseconds);
+                if self.real_time {
+                    std::thread::sleep(std::time::Duration::from_millis(milliseconds));
+                }
+                self.rip += 1;
+            }
+
+            Instruction::Cmp(dst, src) => {
+                let dst_value = self.value_or_label(dst)?;
+                let src_value = self.value_or_label(src)?;
+                self.compare(dst_value, src_value);
+                self.rip += 1;
+            }
+
+            // Flags of `dst & src` without storing it, CF and OF are cleared like x86 does
+            Instruction::Test(dst, src) => {
+                let result = self.constant_or_register(dst)? & self.constant_or_register(src)?;
+                self.zf = (result == 0) as u8;
+                self.cf = 0;
+                self.sf = (result < 0) as u8;
+                self.of = 0;
+                self.pf = parity(result);
+                self.rip += 1;
+            }
+
+            // Flags of `cmp value, 0`, which never borrows or overflows
+            Instruction::Cmpz(value) => {
+                let value = self.constant_or_register(value)?;
+                self.zf = (value == 0) as u8;
+                self.cf = 0;
+                self.sf = (value < 0) as u8;
+                self.of = 0;
+                self.pf = parity(value);
+                self.rip += 1;
+            }
+
+            // Flags as if comparing against the range: ZF inside it, CF and SF below it, PF from the
+            // distance to the nearest bound
+            Instruction::Between(value, low, high) => {
+                let value = self.constant_or_register(value)?;
+                let low = self.constant_or_register(low)?;
+                let high = self.constant_or_register(high)?;
+
+                let distance = if value < low {
+                    value.wrapping_sub(low)
+                } else if value > high {
+                    value.wrapping_sub(high)
+                } else {
+                    0
+                };
+                self.zf = (low..=high).contains(&value) as u8;
+                self.cf = (value < low) as u8;
+                self.sf = (value < low) as u8;
+                self.of = 0;
+                self.pf = parity(distance);
+                self.rip += 1;
+            }
+
+            Instruction::Jmp(label) => {
+                self.rip = program.target(label)?;
+            }
+
+            Instruction::Jne(label) => {
+                if self.holds(Condition::Ne) {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Je(label) => {
+                if self.holds(Condition::Eq) {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Jge(label) => {
+                if self.holds(Condition::Ge) {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Jg(label) => {
+                if self.holds(Condition::Gt) {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Jle(label) => {
+                if self.holds(Condition::Le) {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Jl(label) => {
+                if self.holds(Condition::Lt) {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Jcxz(counter, label) => {
+                if self.constant_or_register(counter)? == 0 {
+                    self.rip = program.target(label)?;
+                } else {
+                    self.rip += 1;
+                }
+            }
+
+            Instruction::Read(dst) => {
+                let value = self.input.pop_front().ok_or(InterpretError::InputExhausted)?;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Rand(dst) => {
+                // xorshift64*, the top 63 bits keep the value non-negative
+                self.random ^= self.random >> 12;
+                self.random ^= self.random << 25;
+                self.random ^= self.random >> 27;
+                let value = (self.random.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 1) as i64;
+                self.write(dst, value)?;
+                self.rip += 1;
+            }
+
+            Instruction::Msg(args) => {
+                self.out = self.message(args)?;
+                let event = self.next_event();
+                if let Some(sink) = self.sink.as_mut() {
+                    sink.write_sequenced(event, &self.out);
+                }
+                self.rip += 1;
+            }
+
+            Instruction::MsgErr(args) => {
+                let message = self.message(args)?;
+                let event = self.next_event();
+                match self.sink.as_mut() {
+                    Some(sink) => sink.write_error(event, &message),
+                    None => NoSink.write_error(event, &message),
+                }
+                self.rip += 1;
+            }
+
+            Instruction::CtxSave(bank) => {
+                self.banks.insert(bank.clone(), self.register.clone());
+                self.rip += 1;
+            }
+
+            Instruction::CtxLoad(bank) => {
+                let saved = self.bank(bank)?.clone();
+                if let Some(writes) = self.writes.as_mut() {
+                    writes.extend(saved.iter().map(|(name, &value)| (name.to_string(), value)));
+                }
+                self.register = saved;
+                self.rip += 1;
+            }
+
+            Instruction::CtxCopyPrefix(bank, prefix) => {
+                let copied: Vec<(Symbol, i64)> = self.bank(bank)?.iter()
+                    .filter(|(name, _)| name.starts_with(prefix.as_str()))
+                    .map(|(name, &value)| (name.clone(), value))
+                    .collect();
+                for (name, value) in copied {
+                    self.write(&name, value)?;
+                }
+                self.rip += 1;
+            }
+
+            Instruction::Abort(args) => {
+                return Ok(Some(Termination::Aborted(self.message(args)?)));
+            }
+
+            Instruction::Command(name, args) => {
+                let values = args.iter().map(|arg| self.constant_or_register(arg)).collect::<Result<Vec<_>, _>>()?;
+                // Decoded instructions may name a command the program has no handler for
+                let handler = program.commands.get(name.as_str()).ok_or_else(|| InterpretError::UnknownInstruction {
+                    mnemonic: name.to_string(),
+                    line: program.lines[self.rip],
+                })?;
+                if let Some(value) = handler(&values) {
+                    self.write(&Symbol::new(ACCUMULATOR), value)?;
+                }
+                self.rip += 1;
+            }
+
+            Instruction::Flush => {
+                if let Some(sink) = self.sink.as_mut() {
+                    sink.flush();
+                }
+                self.rip += 1;
+            }
+
+            Instruction::Ret(count) => {
+                let line = program.lines[self.rip];
+                if self.stack.is_empty() {
+                    return Err(InterpretError::StackUnderflow { line });
+                }
+                // A `call_expr` takes the value its callee pushed last, the arguments sit below it. Both
+                // have to be there before anything is popped
+                let result = self.results.last().cloned().flatten();
+                let remaining = self.values.len()
+                    .checked_sub((*count as usize).saturating_add(result.is_some() as usize))
+                    .ok_or(InterpretError::EmptyValueStack)?;
+                self.rip = self.stack.pop().unwrap();
+                self.results.pop();
+                if let Some(dst) = result {
+                    let value = self.values.pop().unwrap();
+                    self.write(&dst, value)?;
+                }
+                self.values.truncate(remaining);
+            }
+
+            Instruction::End => {
+                return Ok(Some(Termination::End(self.out.to_owned())));
+            }
+
+            Instruction::Pad(_) | Instruction::Cond(..) | Instruction::Function(_) | Instruction::Nop => {
+                self.rip += 1;
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Pause before the first instruction after `label`, returning its index
+    pub fn set_breakpoint(&mut self, label: &str) -> Result<usize, InterpretError> {
+        let index = self.program.functions.get(&Symbol::new(label))
+            .and_then(|&target| self.program.resolve(target))
+            .ok_or_else(|| InterpretError::UndefinedLabel(label.to_string()))?;
+        self.breakpoints.insert(index);
+        Ok(index)
+    }
+
+    pub fn set_breakpoint_at(&mut self, index: usize) {
+        self.breakpoints.insert(index);
+    }
+
+    // Whether a breakpoint was set at `index`
+    pub fn clear_breakpoint(&mut self, index: usize) -> bool {
+        self.breakpoints.remove(&index)
+    }
+
+    // Value of the register `name`, None when the program never wrote it even though it reads as 0
+    pub fn register(&self, name: &str) -> Option<i64> {
+        self.register.get(&Symbol::new(name)).copied()
+    }
+
+    // Every register the program wrote with its value, sorted by name
+    pub fn registers(&self) -> impl Iterator<Item = (&str, i64)> {
+        let mut registers: Vec<(&str, i64)> = self.register.iter().map(|(name, &value)| (name.as_str(), value)).collect();
+        registers.sort();
+        registers.into_iter()
+    }
+
+    // Return addresses of the calls in progress, the innermost last. Each is the index of the
+    // instruction after its `call`; values pushed with `push` live on a stack of their own
+    pub fn stack(&self) -> &[usize] {
+        &self.stack
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags { zf: self.zf, cf: self.cf, sf: self.sf, of: self.of, pf: self.pf }
+    }
+
+    // Index of the instruction that runs next
+    pub fn rip(&self) -> usize {
+        self.rip
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            steps: self.steps,
+            cycles: self.cycles,
+            peak_stack_depth: self.peak_stack,
+            registers: self.register.len(),
+            memory_bytes: self.memory.len(),
+        }
+    }
+
+    // Messages, `msgerr` messages and instructions run emitted so far, the same with `step` and `run`.
+    // Each got the count before it as its sequence number, so they can be merged in the order they happened
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    fn next_event(&mut self) -> u64 {
+        self.events += 1;
+        self.events - 1
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Simulated time in milliseconds, advanced by `sleep`
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // Execution count of every instruction
+    pub fn profile(&self) -> &[u64] {
+        &self.profile
+    }
+
+    // Execution count of every source line holding an executable instruction
+    pub fn line_profile(&self) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for (index, count) in self.profile.iter().enumerate() {
+            if self.program.instructions[index].is_executable() && self.program.lines[index] > 0 {
+                *lines.entry(self.program.lines[index]).or_insert(0) += count;
+            }
+        }
+        lines
+    }
+
+    fn bank(&self, name: &Symbol) -> Result<&SymbolMap<i64>, InterpretError> {
+        self.banks.get(name).ok_or_else(|| InterpretError::UnknownBank {
+            bank: name.to_string(),
+            line: self.program.lines[self.rip],
+        })
+    }
+
+    fn register_mut(&mut self, name: &Symbol) -> Result<&mut i64, InterpretError> {
+        if let Some(limit) = self.max_registers {
+            if self.register.len() >= limit && !self.register.contains_key(name) {
+                return Err(InterpretError::TooManyRegisters { limit, register: name.to_string() });
+            }
+        }
+
+        Ok(self.register.entry(name.clone()).or_insert(0))
+    }
+
+    // Truncate to the register width
+    fn mask(&self, value: i64) -> i64 {
+        match self.width.map(Width::bits) {
+            Some(bits) if bits < 64 => value & ((1 << bits) - 1),
+            _ => value,
+        }
+    }
+
+    // Result of inc, dec, add, sub or mul into `dst`. With a register width set, ZF tells whether the
+    // truncated result is zero and CF whether it didn't fit. The checked mode and the checked strictness
+    // policy fail when it doesn't
+    fn arithmetic(&mut self, dst: &Symbol, result: i128) -> Result<i64, InterpretError> {
+        let truncated = match (self.width.map(Width::bits), self.arithmetic) {
+            (Some(bits), ArithmeticMode::Saturating) if bits < 64 => result.clamp(0, (1 << bits) - 1) as i64,
+            (_, ArithmeticMode::Saturating) => result.clamp(i64::MIN.into(), i64::MAX.into()) as i64,
+            (Some(bits), _) if bits < 64 => (result & ((1 << bits) - 1)) as i64,
+            _ => result as i64,
+        };
+        let checked = self.arithmetic == ArithmeticMode::Checked || self.strictness == StrictnessPolicy::Checked;
+        if checked && i128::from(truncated) != result {
+            return Err(InterpretError::Overflow { register: dst.to_string(), line: self.program.lines[self.rip] });
+        }
+        if self.width.is_some() {
+            self.zf = (truncated == 0) as u8;
+            self.cf = (i128::from(truncated) != result) as u8;
+        }
+        Ok(truncated)
+    }
+
+    // Amount of a shift, None when every bit is shifted out. Counts outside 0 to 63 are masked to
+    // their low 6 bits like x86 does, unless the strictness policy says otherwise
+    fn shift_count(&self, src: &Symbol) -> Result<Option<u32>, InterpretError> {
+        let count = self.constant_or_register(src)?;
+        match count {
+            0..=63 => Ok(Some(count as u32)),
+            _ => match self.strictness {
+                StrictnessPolicy::Lenient => Ok(None),
+                StrictnessPolicy::Hardware => Ok(Some((count & 63) as u32)),
+                StrictnessPolicy::Checked => Err(InterpretError::InvalidShift { count, line: self.program.lines[self.rip] }),
+            },
+        }
+    }
+
+    // Quotient of `div` into `dst`, the lenient policy makes dividing by zero give 0
+    fn divide(&self, dst: &Symbol, lhs: i64, rhs: i64) -> Result<i64, InterpretError> {
+        let line = self.program.lines[self.rip];
+        match rhs {
+            0 if self.strictness == StrictnessPolicy::Lenient => Ok(0),
+            0 => Err(InterpretError::DivisionByZero { register: dst.to_string(), line }),
+            // The one quotient that doesn't fit
+            -1 if lhs == i64::MIN && self.strictness == StrictnessPolicy::Lenient => Ok(i64::MIN),
+            -1 if lhs == i64::MIN => Err(InterpretError::Overflow { register: dst.to_string(), line }),
+            _ => Ok(self.rounding.divide(lhs, rhs)),
+        }
+    }
+
+    // Negation for neg and abs. i64::MIN has none that fits, only the lenient policy lets it stay i64::MIN
+    fn negate(&self, dst: &Symbol, value: i64) -> Result<i64, InterpretError> {
+        match value.checked_neg() {
+            Some(negated) => Ok(negated),
+            None if self.strictness == StrictnessPolicy::Lenient => Ok(i64::MIN),
+            None => Err(InterpretError::Overflow { register: dst.to_string(), line: self.program.lines[self.rip] }),
+        }
+    }
+
+    // Remainder of `mod` into `dst`, matching the quotient `div` would give
+    fn remainder(&self, dst: &Symbol, lhs: i64, rhs: i64) -> Result<i64, InterpretError> {
+        match rhs {
+            0 => self.divide(dst, lhs, rhs),
+            // Nothing is left over, even when the quotient doesn't fit
+            -1 => Ok(0),
+            _ => Ok(lhs - self.rounding.divide(lhs, rhs) * rhs),
+        }
+    }
+
+    // Store into a register or, for `[...]` operands, into memory
+    fn write(&mut self, dst: &Symbol, value: i64) -> Result<(), InterpretError> {
+        let value = self.mask(value);
+        if dst.starts_with('[') {
+            let address = self.address(dst)?;
+            for (offset, byte) in self.endianness.store(value).into_iter().enumerate() {
+                self.memory.insert(address + offset as i64, byte);
+            }
+            if let Some(writes) = self.writes.as_mut() {
+                writes.push((format!("[{}]", address), value));
+            }
+        } else {
+            *self.register_mut(dst)? = value;
+            if let Some(writes) = self.writes.as_mut() {
+                writes.push((dst.to_string(), value));
+            }
+        }
+        Ok(())
+    }
+
+    // Effective address of a memory operand: a sum of registers and constants like `[a+b-4]`
+    fn address(&self, operand: &Symbol) -> Result<i64, InterpretError> {
+        let Some(terms) = self.program.addresses.get(operand) else {
+            return self.address_of(operand);
+        };
+        let mut address: i64 = 0;
+        for (negated, term) in terms {
+            let value = self.constant_or_register(term)?;
+            address = address.wrapping_add(if *negated { value.wrapping_neg() } else { value });
+        }
+        valid_address(address)
+    }
+
+    // Same for an operand the program doesn't hold on its own, like one in a message condition
+    fn address_of(&self, operand: &str) -> Result<i64, InterpretError> {
+        let expression = operand.trim_start_matches('[').trim_end_matches(']');
+        let mut address: i64 = 0;
+        for term in address_terms(expression) {
+            let value = match term.strip_prefix('-') {
+                Some(negated) if !negated.starts_with(|c: char| c.is_ascii_digit()) => self.value(negated)?.wrapping_neg(),
+                _ => self.value(term)?
+            };
+            address = address.wrapping_add(value);
+        }
+        valid_address(address)
+    }
+
+    fn load(&self, address: i64) -> i64 {
+        let mut bytes = [0; 8];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = *self.memory.get(&(address + offset as i64)).unwrap_or(&0);
+        }
+        self.endianness.load(bytes)
+    }
+
+    fn constant_or_register(&self, src: &Symbol) -> Result<i64, InterpretError> {
+        match src.parse::<i64>() {
+            Ok(r) => Ok(r),
+            _ if src.starts_with('[') => Ok(self.load(self.address(src)?)),
+            _ if src.starts_with('\'') => pack_chars(src)
+                .ok_or_else(|| InterpretError::InvalidLiteral { literal: src.to_string(), line: self.program.lines[self.rip] }),
+            _ if src == "rip" => Ok(self.rip as i64),
+            _ if src == "$steps" => Ok(self.steps as i64),
+            _ if src == "$cycles" => Ok(self.cycles as i64),
+            // 0 inside the built-in subroutines
+            _ if src == "$line" => Ok(self.program.lines[self.rip] as i64),
+            // Hexadecimal, binary and `_` separated literals, after the names above since no name starts like a number.
+            // Malformed ones are rejected while parsing
+            _ if src.starts_with(|c: char| c.is_ascii_digit() || c == '-') => Ok(parse_integer(src).unwrap_or(0)),
+            // Constants take precedence over registers of the same name
+            _ => Ok(*self.program.constants.get(src).or_else(|| self.register.get(src)).unwrap_or(&0))
+        }
+    }
+
+    // Like `constant_or_register` for text inside another operand. It is only looked up in the names
+    // of the program, interning it while running would grow them with every run
+    fn value(&self, src: &str) -> Result<i64, InterpretError> {
+        match self.program.names.lookup(src) {
+            Some(symbol) => self.constant_or_register(&symbol),
+            // Names the program doesn't use itself, like the registers of a loaded state
+            None => self.constant_or_register(&Symbol::new(src)),
+        }
+    }
+
+    // Push the return address of a call at `rip`, with the register receiving its result if any
+    fn enter(&mut self, result: Option<Symbol>) -> Result<(), InterpretError> {
+        if self.stack.len() >= self.max_stack_depth {
+            return Err(InterpretError::StackOverflow { depth: self.stack.len(), line: self.program.lines[self.rip] });
+        }
+        self.stack.push(self.rip + 1);
+        self.results.push(result);
+        self.peak_stack = self.peak_stack.max(self.stack.len());
+        Ok(())
+    }
+
+    // Whether the flags of the last `cmp` satisfy `condition`, shared by conditional jumps and suffixes
+    fn holds(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Eq => self.zf == 1,
+            Condition::Ne => self.zf != 1,
+            // Signed order like x86, the subtraction went negative unless it overflowed
+            Condition::Gt => self.zf == 0 && self.sf == self.of,
+            Condition::Ge => self.sf == self.of,
+            Condition::Lt => self.sf != self.of,
+            Condition::Le => self.zf == 1 || self.sf != self.of,
+        }
+    }
+
+    // Flags of `dst - src` like x86: CF is the unsigned borrow, SF and OF give the signed order
+    fn compare(&mut self, dst_value: i64, src_value: i64) {
+        self.zf = (dst_value == src_value) as u8;
+        self.cf = ((dst_value as u64) < (src_value as u64)) as u8;
+        let (difference, overflow) = dst_value.overflowing_sub(src_value);
+        self.sf = (difference < 0) as u8;
+        self.of = overflow as u8;
+        self.pf = parity(difference);
+    }
+
+    // Flags packed into one value: bit 0 is ZF, bit 1 CF, bit 2 SF, bit 3 OF and bit 4 PF
+    fn packed_flags(&self) -> i64 {
+        (self.zf as i64) | (self.cf as i64) << 1 | (self.sf as i64) << 2 | (self.of as i64) << 3 | (self.pf as i64) << 4
+    }
+
+    fn set_flags(&mut self, flags: i64) {
+        self.zf = (flags & 1) as u8;
+        self.cf = (flags >> 1 & 1) as u8;
+        self.sf = (flags >> 2 & 1) as u8;
+        self.of = (flags >> 3 & 1) as u8;
+        self.pf = (flags >> 4 & 1) as u8;
+    }
+
+    // Text of `msg` and `abort`: quoted parts as they are, everything else by value
+    fn message(&self, args: &[Symbol]) -> Result<String, InterpretError> {
+        let mut opened = false;
+        // Concat arguments
+        args.iter().map(|i| {
+            if i == "'" {
+                if !opened {
+                    opened = !opened;
+                    Ok(String::from(","))
+                } else {
+                    opened = !opened;
+                    Ok(String::from(" "))
+                }
+            } else if let Some(segment) = i.strip_prefix("?(") {
+                self.conditional_segment(segment)
+            } else if i.contains("'") {
+                Ok(i.trim_matches('\'').to_string())
+            } else {
+                self.constant_or_register(i).map(|value| value.to_string())
+            }
+        }).collect()
+    }
+
+    // `?(condition) then : else` inside a message. The condition is `zf`, `cf` or a register that holds when
+    // nonzero, each branch is quoted text or a value and the `: else` part may be left out
+    fn conditional_segment(&self, segment: &str) -> Result<String, InterpretError> {
+        let (condition, branches) = segment.split_once(')').unwrap_or((segment, ""));
+        let holds = match condition.trim() {
+            "zf" => self.zf != 0,
+            "cf" => self.cf != 0,
+            register => self.value(register)? != 0,
+        };
+
+        let mut quoted = false;
+        let colon = branches.char_indices().find(|&(_, c)| {
+            if c == '\'' {
+                quoted = !quoted;
+            }
+            c == ':' && !quoted
+        });
+        let branch = match (holds, colon) {
+            (true, Some((position, _))) => &branches[..position],
+            (true, None) => branches,
+            (false, Some((position, _))) => &branches[position + 1..],
+            (false, None) => "",
+        };
+
+        match branch.trim() {
+            "" => Ok(String::new()),
+            text if text.starts_with('\'') => Ok(text.trim_matches('\'').to_string()),
+            value => self.value(value).map(|value| value.to_string()),
+        }
+    }
+
+    // Like `constant_or_register`, but a bare identifier that is not a register resolves to the
+    // instruction index its label jumps to
+    fn operand(&self, operand: &Operand) -> Result<i64, InterpretError> {
+        match operand {
+            Operand::Value(value) => Ok(*value),
+            Operand::Register(name) => Ok(self.register.get(name).copied().unwrap_or(0)),
+            Operand::Other(src) => self.constant_or_register(src),
+        }
+    }
+
+    fn value_or_label(&self, src: &Symbol) -> Result<i64, InterpretError> {
+        match self.program.functions.get(src) {
+            Some(&target) if !self.register.contains_key(src) && !self.program.constants.contains_key(src) => Ok(target as i64),
+            _ => self.constant_or_register(src),
+        }
+    }
+}
+
+// Instruction with its operands decoded and its jump target resolved, run instead of going through
+// the dispatch in `execute`
+type Prepared<'a> = Box<dyn Fn(&mut Interpreter<'a>) -> Result<(), InterpretError> + 'a>;
+
+// Operand of a prepared instruction
+enum Operand {
+    // A number, character literal or constant
+    Value(i64),
+    Register(Symbol),
+    // Memory and the pseudo-registers, read like in any other instruction
+    Other(Symbol),
+}
+
+impl Operand {
+    // Same order as `constant_or_register`
+    fn decode(program: &Program, operand: &Symbol) -> Operand {
+        match operand.parse::<i64>() {
+            Ok(value) => Operand::Value(value),
+            _ if operand.starts_with('[') || matches!(operand.as_str(), "rip" | "$steps" | "$cycles" | "$line") => Operand::Other(operand.clone()),
+            _ if operand.starts_with('\'') => pack_chars(operand).map_or_else(|| Operand::Other(operand.clone()), Operand::Value),
+            _ if operand.starts_with(|c: char| c.is_ascii_digit() || c == '-') => Operand::Value(parse_integer(operand).unwrap_or(0)),
+            _ => match program.constants.get(operand) {
+                Some(&value) => Operand::Value(value),
+                None => Operand::Register(operand.clone()),
+            },
+        }
+    }
+}
+
+// Handler of the instruction at `index` if it is one that loops spend their time in, None for the
+// rest. It does what `execute` does for it
+fn prepare<'a>(program: &Program<'a>, index: usize) -> Option<Prepared<'a>> {
+    let decode = |operand: &Symbol| Operand::decode(program, operand);
+    let arithmetic = |dst: &Symbol, src: Operand, op: fn(i128, i128) -> i128| -> Option<Prepared<'a>> {
+        let (dst, dst_operand) = (dst.clone(), decode(dst));
+        Some(Box::new(move |interpreter| {
+            let src_value = interpreter.operand(&src)?;
+            let dst_value = interpreter.operand(&dst_operand)?;
+            let value = interpreter.arithmetic(&dst, op(i128::from(dst_value), i128::from(src_value)))?;
+            interpreter.write(&dst, value)?;
+            interpreter.rip += 1;
+            Ok(())
+        }))
+    };
+
+    match &program.instructions[index] {
+        Instruction::Mov(dst, src) if dst != "rip" && dst != src => {
+            let (dst, src) = (dst.clone(), decode(src));
+            Some(Box::new(move |interpreter| {
+                let value = interpreter.operand(&src)?;
+                interpreter.write(&dst, value)?;
+                interpreter.rip += 1;
+                Ok(())
+            }))
+        }
+        Instruction::Inc(dst) => arithmetic(dst, Operand::Value(1), |dst, src| dst + src),
+        Instruction::Dec(dst) => arithmetic(dst, Operand::Value(1), |dst, src| dst - src),
+        Instruction::Add(dst, src) => arithmetic(dst, decode(src), |dst, src| dst + src),
+        Instruction::Sub(dst, src) => arithmetic(dst, decode(src), |dst, src| dst - src),
+        Instruction::Mul(dst, src) => arithmetic(dst, decode(src), |dst, src| dst * src),
+        // Labels compared by their index are left to `execute`, a register of the same name wins once written
+        Instruction::Cmp(dst, src) if !program.functions.contains_key(dst) && !program.functions.contains_key(src) => {
+            let (dst, src) = (decode(dst), decode(src));
+            Some(Box::new(move |interpreter| {
+                let dst_value = interpreter.operand(&dst)?;
+                let src_value = interpreter.operand(&src)?;
+                interpreter.compare(dst_value, src_value);
+                interpreter.rip += 1;
+                Ok(())
+            }))
+        }
+        Instruction::Jmp(label) => {
+            let target = program.target(label).ok()?;
+            Some(Box::new(move |interpreter| {
+                interpreter.rip = target;
+                Ok(())
+            }))
+        }
+        other => {
+            let (condition, label) = other.branch()?;
+            let target = program.target(label).ok()?;
+            Some(Box::new(move |interpreter| {
+                interpreter.rip = if interpreter.holds(condition) { target } else { interpreter.rip + 1 };
+                Ok(())
+            }))
+        }
+    }
+}
+
+// PF of a result: 1 when its low byte has an even number of set bits
+fn parity(value: i64) -> u8 {
+    (value as u8).count_ones().is_multiple_of(2) as u8
+}
+
+// Seed for an interpreter created without `with_seed`, two of them never share one
+fn fresh_seed() -> u64 {
+    static CREATED: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    nanos ^ CREATED.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+// xorshift gets stuck on zero, so that one state is moved
+fn seed_state(seed: u64) -> u64 {
+    (seed ^ 0x9E37_79B9_7F4A_7C15).max(1)
+}
+
+fn join(symbols: &[Symbol], separator: &str) -> String {
+    symbols.iter().map(Symbol::as_str).collect::<Vec<_>>().join(separator)
+}
+
+// Terms of an address expression, `a-4+b` gives `a`, `-4` and `b`
+// Every access spans 8 bytes
+fn valid_address(address: i64) -> Result<i64, InterpretError> {
+    if !(0..=i64::MAX - 7).contains(&address) {
+        return Err(InterpretError::InvalidAddress(address));
+    }
+    Ok(address)
+}
+
+// Terms of a memory operand like `[a+b-4]` with whether each is subtracted, interned while parsing
+// so that running doesn't have to. A negative number stays a single term
+fn memory_terms(operand: &str, names: &mut SymbolTable) -> Vec<(bool, Symbol)> {
+    address_terms(operand.trim_start_matches('[').trim_end_matches(']'))
+        .map(|term| match term.strip_prefix('-') {
+            Some(negated) if !negated.starts_with(|c: char| c.is_ascii_digit()) => (true, names.intern(negated)),
+            _ => (false, names.intern(term)),
+        })
+        .collect()
+}
+
+fn address_terms(expression: &str) -> impl Iterator<Item = &str> {
+    let mut starts: Vec<usize> = expression.match_indices(['+', '-'])
+        .map(|(index, _)| index)
+        .filter(|&index| index > 0)
+        .collect();
+    starts.insert(0, 0);
+    starts.push(expression.len());
+
+    (0..starts.len() - 1)
+        .map(move |i| expression[starts[i]..starts[i + 1]].trim_start_matches('+'))
+        .filter(|term| !term.is_empty())
+}
+
+// Count operand of `ret` and `nop`, an integer that isn't negative
+fn parse_count(text: &str) -> Option<u64> {
+    parse_integer(text).and_then(|count| u64::try_from(count).ok())
+}
+
+// Decimal, `0x` hexadecimal or `0b` binary integer, each optionally negative and with `_` between digits
+fn parse_integer(text: &str) -> Option<i64> {
+    if let Ok(value) = text.parse() {
+        return Some(value);
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    // Names may contain `_` too, but only a number starts with a digit
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let digits = digits.replace('_', "");
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        _ => (10, &digits[..]),
+    };
+    // `from_str_radix` would take another sign
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+    let value = i128::from_str_radix(digits, radix).ok()?;
+    i64::try_from(if negative { -value } else { value }).ok()
+}
+
+// Pack a quoted literal of up to 8 ASCII chars into an integer, first char in the most significant byte.
+// A single character of any kind gives its code point
+fn pack_chars(literal: &str) -> Option<i64> {
+    let chars = literal.strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))?;
+    let mut iter = chars.chars();
+    if let (Some(c), None) = (iter.next(), iter.next()) {
+        return Some(c as i64);
+    }
+    if chars.is_empty() || chars.len() > 8 || !chars.is_ascii() {
+        return None;
+    }
+
+    Some(chars.bytes().fold(0, |packed, byte| packed << 8 | byte as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_interpreter() {
+        let programs_list = &[
+            "\n; My first program\nmov  a, 5\ninc  a\ncall function\nmsg  '(5+1)/2 = ', a    ; output message\nend\n\nfunction:\n    div  a, 2\n    ret\n",
+            "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n",
+            "\ncall  func1\ncall  print\nend\n\nfunc1:\n    call  func2\n    ret\n\nfunc2:\n    ret\n\nprint:\n    msg 'This program should return null'\n",
+            "\n            mov a, 173   ; instruction mov a, 173\n            mov k, 88   ; instruction mov k, 88\n            call func\n            msg 'Random result: ', o\n            end\n            func:\n              cmp a, k\n              jne exit\n              mov o, a\n              add o, k\n              ret\n            ; Do nothing\n            exit:\n              msg 'Do nothing'",
+            "\n            mov q, 86   ; instruction mov q, 86\n            mov m, 73   ; instruction mov m, 73\n            call func\n            msg 'Random result: ', g\n            end\n            func:\n              cmp q, m\n              jl exit\n              mov g, q\n              div g, m\n              ret\n            ; Do nothing\n            exit:\n              msg 'Do nothing'"
+        ];
+
+        assert_eq!(Ok(Some(String::from("(5+1)/2 = 3"))), Interpreter::interpret(programs_list[0]).1);
+        assert_eq!(Ok(Some(String::from("5! = 120"))), Interpreter::interpret(programs_list[1]).1);
+        assert_eq!(Ok(None), Interpreter::interpret(programs_list[2]).1);
+        assert_eq!(Ok(None), Interpreter::interpret(programs_list[3]).1);
+        assert_eq!(Ok(Some(String::from("Random result: 1"))), Interpreter::interpret(programs_list[4]).1);
+    }
+
+    #[test]
+    fn check_operand_whitespace() {
+        match Instruction::from(String::from("mov [ a + 2 ], - 5")) {
+            Instruction::Mov(dst, src) => {
+                assert_eq!("[a+2]", dst);
+                assert_eq!("-5", src);
+            }
+            other => panic!("unexpected instruction {:?}", other),
+        }
+
+        let program = "mov a, - 5\nadd a, +  7\nmsg 'a = ', a\nend\n";
+        assert_eq!(Ok(Some(String::from("a = 2"))), Interpreter::interpret(program).1);
+    }
+
+    #[test]
+    fn check_max_registers() {
+        let program = "mov a, 1\nmov b, 2\nmov c, 3\nmov a, 4\nend\n";
+
+        let mut interpreter = Interpreter::new(program).with_max_registers(3);
+        assert_eq!(Ok(Termination::End(String::new())), interpreter.run());
+
+        let mut interpreter = Interpreter::new(program).with_max_registers(2);
+        assert_eq!(
+            Err(InterpretError::TooManyRegisters { limit: 2, register: String::from("c") }),
+            interpreter.run()
+        );
+    }
+
+    #[test]
+    fn check_jcxz() {
+        let program = "cmp 1, 2\njcxz a, done\nmsg 'not taken'\nend\ndone:\n    msg 'taken'\n    end\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("taken"))), output);
+        assert_eq!(1, interpreter.cf);
+        assert_eq!(None, interpreter.register.get(&Symbol::from("a")));
+
+        let program = "mov a, 3\njcxz a, done\nmsg 'not taken'\nend\ndone:\n    msg 'taken'\n    end\n";
+        assert_eq!(Ok(Some(String::from("not taken"))), Interpreter::interpret(program).1);
+    }
+
+    #[test]
+    fn check_mov_rip() {
+        let program = "mov a, 4\nmov rip, a\nmsg 'skipped'\nend\nmov b, rip\nmsg 'jumped to ', b\nend\n";
+        assert_eq!(Ok(Some(String::from("jumped to 4"))), Interpreter::interpret(program).1);
+
+        let mut interpreter = Interpreter::new("mov rip, 10\nend\n");
+        assert_eq!(Err(InterpretError::InvalidJumpTarget(10)), interpreter.run());
+
+        let mut interpreter = Interpreter::new("mov rip, -1\nend\n");
+        assert_eq!(Err(InterpretError::InvalidJumpTarget(-1)), interpreter.run());
+
+        // Index 2 is the label and 4 the blank line after it
+        let program = "mov a, 2\nmov rip, a\ntarget:\n    msg 'landed'\n\n    end\n";
+        let mut interpreter = Interpreter::new(program);
+        assert_eq!(Err(InterpretError::InvalidJumpTarget(2)), interpreter.run());
+        let blank = program.replacen("mov a, 2", "mov a, 4", 1);
+        assert_eq!(Err(InterpretError::InvalidJumpTarget(4)), Interpreter::new(&blank).run());
+        let program = program.replacen("mov a, 2", "mov a, 3", 1);
+        assert_eq!(Ok(Some(String::from("landed"))), Interpreter::interpret(&program).1);
+    }
+
+    #[test]
+    fn check_nop_cycles() {
+        let (interpreter, _) = Interpreter::interpret("mov a, 1\n\nstart:\n    nop 3\n    nop\n    end\n");
+        assert_eq!(6, interpreter.cycles());
+
+        let interpreter = Interpreter::new("nop x\nnop 0x10\nnop -2\nend\n");
+        let invalid = |literal: &str, line| InterpretError::InvalidLiteral { literal: literal.to_string(), line };
+        assert_eq!([invalid("x", 1), invalid("-2", 3)], interpreter.program.errors());
+        assert_eq!(Instruction::Pad(16), interpreter.program.instructions[1]);
+    }
+
+    #[test]
+    fn check_symbols() {
+        let mut program = Program::new("call print\nend\n\nprint:\n    msg 'hi'\n    ret\n; helpers\nzero:\n    mov a, 0\n    ret\n");
+        program.parse();
+        assert_eq!(vec![(String::from("print"), 4), (String::from("zero"), 8)], program.symbols());
+    }
+
+    #[test]
+    fn check_packed_literals() {
+        let (interpreter, _) = Interpreter::interpret("mov a, 'A'\nmov b, 'AB'\nmov c, 'ABCDEFGH'\nend\n");
+        assert_eq!(Some(&65), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(Some(&0x4142), interpreter.register.get(&Symbol::from("b")));
+        assert_eq!(Some(&0x4142434445464748), interpreter.register.get(&Symbol::from("c")));
+
+        let mut interpreter = Interpreter::new("mov a, 'ABCDEFGHI'\nend\n");
+        assert_eq!(Err(InterpretError::InvalidLiteral { literal: String::from("'ABCDEFGHI'"), line: 1 }), interpreter.run());
+    }
+
+    #[test]
+    fn check_output_sink() {
+        let mut messages = Vec::new();
+        let program = "mov a, 1\nloop:\n    msg 'a = ', a\n    inc a\n    cmp a, 3\n    jle loop\nend\n";
+
+        let mut interpreter = Interpreter::new(program).with_sink(&mut messages);
+        assert_eq!(Ok(Termination::End(String::from("a = 3"))), interpreter.run());
+        drop(interpreter);
+
+        assert_eq!(vec!["a = 1", "a = 2", "a = 3"], messages);
+    }
+
+    #[test]
+    fn check_sequenced_output() {
+        #[derive(Default)]
+        struct Streams {
+            out: Vec<(u64, String)>,
+            err: Vec<(u64, String)>,
+        }
+        impl OutputSink for Streams {
+            fn write_message(&mut self, _: &str) {
+                unreachable!()
+            }
+
+            fn write_sequenced(&mut self, sequence: u64, message: &str) {
+                self.out.push((sequence, message.to_string()));
+            }
+
+            fn write_error(&mut self, sequence: u64, message: &str) {
+                self.err.push((sequence, message.to_string()));
+            }
+        }
+
+        let mut streams = Streams::default();
+        let program = "mov a, 1\nmsg 'a = ', a\nmsgerr 'checking ', a\nflush\nmsg 'done'\nmsgerr 'late'\nend\n";
+        let mut interpreter = Interpreter::new(program).with_sink(BufferedSink::new(&mut streams));
+        let (trace, result) = Trace::record(&mut interpreter);
+        assert_eq!(Ok(Termination::End(String::from("done"))), result);
+        assert_eq!(11, interpreter.events());
+        drop(interpreter);
+
+        // `done` is held back until the run ends, after `late` went out, its number still puts it first
+        let message = |(sequence, text): (u64, &str)| (sequence, String::from(text));
+        assert_eq!(vec![message((1, "a = 1")), message((6, "done"))], streams.out);
+        assert_eq!(vec![message((3, "checking 1")), message((8, "late"))], streams.err);
+        assert_eq!(vec![0, 2, 4, 5, 7, 9], trace.steps.iter().map(|step| step.event).collect::<Vec<_>>());
+
+        // Running without stepping numbers them the same
+        let mut streams = Streams::default();
+        let mut interpreter = Interpreter::new(program).with_sink(BufferedSink::new(&mut streams));
+        assert!(interpreter.run().is_ok());
+        assert_eq!(11, interpreter.events());
+        drop(interpreter);
+        assert_eq!(vec![message((1, "a = 1")), message((6, "done"))], streams.out);
+        assert_eq!(vec![message((3, "checking 1")), message((8, "late"))], streams.err);
+    }
+
+    #[test]
+    fn check_flush() {
+        let mut messages = Vec::new();
+        let program = "msg 'one'\nmsg 'two'\nflush\nmsg 'three'\nend\n";
+
+        let mut interpreter = Interpreter::new(program).with_sink(BufferedSink::new(&mut messages));
+        assert_eq!(Ok(Termination::End(String::from("three"))), interpreter.run());
+        drop(interpreter);
+
+        // `three` comes after the last `flush` and is emitted when the run ends
+        assert_eq!(vec!["one", "two", "three"], messages);
+    }
+
+    #[test]
+    fn check_limited_sink() {
+        let mut messages = Vec::new();
+        let program = "mov a, 1\nloop:\n    msg 'a = ', a\n    inc a\n    cmp a, 5\n    jle loop\nend\n";
+
+        let mut interpreter = Interpreter::new(program).with_sink(LimitedSink::new(&mut messages, 2));
+        assert_eq!(Ok(Termination::End(String::from("a = 5"))), interpreter.run());
+        drop(interpreter);
+
+        assert_eq!(vec!["a = 1", "a = 2", "... (3 more)"], messages);
+    }
+
+    #[test]
+    fn check_cmp_char() {
+        let program = "mov a, 65\ncmp a, 'A'\nje equal\nmsg 'not equal'\nend\nequal:\n    msg 'equal'\n    end\n";
+        assert_eq!(Ok(Some(String::from("equal"))), Interpreter::interpret(program).1);
+
+        let program = "mov a, 'a'\ncmp a, 'A'\njg lower\nmsg 'upper'\nend\nlower:\n    msg 'lower'\n    end\n";
+        assert_eq!(Ok(Some(String::from("lower"))), Interpreter::interpret(program).1);
+
+        let program = "mov a, '0'\ncmp a, '0'\nje digit\nmsg 'other'\nend\ndigit:\n    msg 'digit ', a, ' ', b\n    end\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("digit 48 0"))), output);
+        assert_eq!(Some(&48), interpreter.register.get(&Symbol::from("a")));
+
+        // Characters outside ASCII give their code point, but can't be packed with others
+        let program = "mov a, 'é'\nmov b, '€'\nmsg a, ' ', b\nend\n";
+        assert_eq!(Ok(Some(String::from("233 8364"))), Interpreter::interpret(program).1);
+        assert_eq!(None, pack_chars("'é€'"));
+    }
+
+    #[test]
+    fn check_mnemonic_case() {
+        let parse = |line: &str| Instruction::from(String::from(line));
+        assert_eq!(Instruction::Add(Symbol::from("a"), Symbol::from("B")), parse("ADD a, B"));
+        assert_eq!(Instruction::Inc(Symbol::from("a")), parse("Inc a"));
+        assert_eq!(Instruction::Call(Symbol::from("Print")), parse("CALL Print"));
+        assert_eq!(Instruction::Function(Symbol::from("Print")), parse("Print:"));
+        assert_eq!(Instruction::Cond(Condition::Ne, Box::new(Instruction::Mov(Symbol::from("a"), Symbol::from("1")))), parse("MOVNE a, 1"));
+
+        let program = "MOV a, 5\nCall Show\nEND\nShow:\n    Msg 'Value: ', a\n    RET\n";
+        assert_eq!(Ok(Some(String::from("Value: 5"))), Interpreter::interpret(program).1);
+    }
+
+    #[test]
+    fn check_instruction_equality() {
+        let spaced = Instruction::from(String::from("mov   a ,  5"));
+        let compact = Instruction::from(String::from("mov a,5"));
+        assert_eq!(spaced, compact);
+        assert_eq!(Instruction::Mov(Symbol::from("a"), Symbol::from("5")), spaced);
+        assert_ne!(Instruction::from(String::from("mov a, 6")), compact);
+
+        let unique: std::collections::HashSet<Instruction> = vec![spaced, compact, Instruction::Ret(0)].into_iter().collect();
+        assert_eq!(2, unique.len());
+    }
+
+    #[test]
+    fn check_line_range() {
+        let program = "mov a, 1\nmov b, 2\n\nadd a, b\nmul a, 10\nmsg 'a = ', a\nend\n";
+
+        let mut interpreter = Interpreter::new(program).with_line_range(2, 5);
+        assert_eq!(Ok(Termination::LeftRange(String::new())), interpreter.run());
+        assert_eq!(Some(&20), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(Some(&2), interpreter.register.get(&Symbol::from("b")));
+        assert_eq!(5, interpreter.rip);
+
+        let mut interpreter = Interpreter::new(program).with_line_range(3, 4);
+        assert_eq!(Ok(Termination::LeftRange(String::new())), interpreter.run());
+        assert_eq!(Some(&0), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(None, interpreter.register.get(&Symbol::from("b")));
+    }
+
+    #[test]
+    fn check_adc_sbb() {
+        // 128-bit (b:a) + (d:c) where the low words carry into the high words
+        let program = "mov a, -1\nmov b, 1\nmov c, 1\nmov d, 2\nadc a, c\nadc b, d\nend\n";
+        let (interpreter, _) = Interpreter::interpret(program);
+        assert_eq!(Some(&0), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(Some(&4), interpreter.register.get(&Symbol::from("b")));
+        assert_eq!(0, interpreter.cf);
+
+        // 128-bit (b:a) - (d:c) where the low words borrow from the high words
+        let program = "mov a, 0\nmov b, 5\nmov c, 1\nmov d, 2\nsbb a, c\nsbb b, d\nend\n";
+        let (interpreter, _) = Interpreter::interpret(program);
+        assert_eq!(Some(&-1), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(Some(&2), interpreter.register.get(&Symbol::from("b")));
+        assert_eq!(0, interpreter.cf);
+    }
+
+    #[test]
+    fn check_counter_pseudo_registers() {
+        let (interpreter, output) = Interpreter::interpret("mov a, 1\nnop 2\n\nmsg 'steps=', $steps, ' cycles=', $cycles\n");
+        assert_eq!(Ok(None), output);
+        assert_eq!(3, interpreter.steps());
+        assert_eq!(4, interpreter.cycles());
+        assert_eq!("steps=3 cycles=4", interpreter.out);
+    }
+
+    #[test]
+    fn check_label_register_warning() {
+        let mut program = Program::new("mov a, 1\ncall b\nend\nb:\n    ret\n\na:\n    msg a\n    ret\n");
+        program.parse();
+        assert_eq!(&[Warning::LabelShadowsRegister { label: String::from("a"), line: 7 }], program.warnings());
+    }
+
+    #[test]
+    fn check_run_with_input() {
+        let program = "read n\nloop:\n    read x\n    add sum, x\n    dec n\n    cmp n, 0\n    jg loop\nmsg 'sum = ', sum\nend\n";
+
+        let (interpreter, output) = Interpreter::run_with_input(program, &[4, 10, 20, 30, 40]);
+        assert_eq!(Ok(Some(String::from("sum = 100"))), output);
+        assert_eq!(Some(&40), interpreter.register.get(&Symbol::from("x")));
+
+        let (_, output) = Interpreter::run_with_input(program, &[3, 1, 2]);
+        assert_eq!(Err(InterpretError::InputExhausted), output);
+    }
+
+    #[test]
+    fn check_mulmod() {
+        let program = "mov a, 4000000000000000000\nmov b, 3\nmulmod c, a, b, 1000000007\nend\n";
+        let (interpreter, _) = Interpreter::interpret(program);
+        assert_eq!(Some(&((4_000_000_000_000_000_000i128 * 3 % 1_000_000_007) as i64)), interpreter.register.get(&Symbol::from("c")));
+        assert_eq!(Some(&4_000_000_000_000_000_000), interpreter.register.get(&Symbol::from("a")));
+
+        let mut interpreter = Interpreter::new("mulmod c, 2, 3, 0\nend\n");
+        assert_eq!(Err(InterpretError::DivisionByZero { register: String::from("c"), line: 1 }), interpreter.run());
+    }
+
+    #[test]
+    fn check_describe() {
+        let add = Instruction::from(String::from("add a, 5"));
+        assert_eq!("add a, 5", add.to_string());
+        assert_eq!("a = a + 5", add.describe());
+
+        let jump = Instruction::from(String::from("jle  loop"));
+        assert_eq!("jle loop", jump.to_string());
+        assert_eq!("jump to loop if less or equal", jump.describe());
+
+        let msg = Instruction::from(String::from("msg 'a, b = ', a, ', ', b"));
+        assert_eq!("msg 'a, b = ', a, ', ', b", msg.to_string());
+    }
+
+    #[test]
+    fn check_memory_addressing() {
+        // Base + displacement
+        let program = "mov a, 100\nmov [a+8], 42\nmov [ a + 16 ], -7\nmov b, [108]\nmov c, [a + 16]\nend\n";
+        let (interpreter, _) = Interpreter::interpret(program);
+        assert_eq!(Some(&42), interpreter.register.get(&Symbol::from("b")));
+        assert_eq!(Some(&-7), interpreter.register.get(&Symbol::from("c")));
+        assert_eq!(Some(&42), interpreter.memory.get(&108));
+
+        // Base + index, summing an array of three words
+        let program = "mov base, 64\nmov [base], 1\nmov [base+8], 2\nmov [base+16], 3\nmov i, 16\nloop:\n    add sum, [base+i]\n    sub i, 8\n    cmp i, 0\n    jge loop\nadd [base-i], sum\nmsg 'sum = ', sum, ' last = ', [72]\nend\n";
+        assert_eq!(Ok(Some(String::from("sum = 6 last = 8"))), Interpreter::interpret(program).1);
+
+        let mut interpreter = Interpreter::new("mov a, 4\nmov [a-8], 1\nend\n");
+        assert_eq!(Err(InterpretError::InvalidAddress(-4)), interpreter.run());
+
+        // Subtracting the smallest value wraps around instead of overflowing
+        let mut interpreter = Interpreter::new("mov a, -9223372036854775808\nmov b, [0-a]\nmsg b\nend\n");
+        assert_eq!(Err(InterpretError::InvalidAddress(i64::MIN)), interpreter.run());
+        let mut interpreter = Interpreter::new("mov a, -9223372036854775808\ncmp 1, 1\nmsg ?(z) 'set' : [0-a]\nend\n");
+        assert_eq!(Err(InterpretError::InvalidAddress(i64::MIN)), interpreter.run());
+    }
+
+    #[test]
+    fn check_termination() {
+        let mut interpreter = Interpreter::new("mov a, 1\nmsg 'a = ', a\n");
+        assert_eq!(Ok(Termination::FellThrough), interpreter.run());
+        assert_eq!(None, Termination::FellThrough.into_output());
+
+        let mut interpreter = Interpreter::new("mov a, 1\nmsg 'a = ', a\nend\n");
+        assert_eq!(Ok(Termination::End(String::from("a = 1"))), interpreter.run());
+        assert_eq!(Some(String::from("a = 1")), Termination::End(String::from("a = 1")).into_output());
+    }
+
+    #[test]
+    fn check_zero() {
+        let (interpreter, output) = Interpreter::interpret("mov a, 1\nmov b, 2\nzero a, b, c\nmsg a, b, c\nend\n");
+        assert_eq!(Ok(Some(String::from("000"))), output);
+        assert_eq!(Some(&0), interpreter.register.get(&Symbol::from("c")));
+        assert_eq!("a = b = c = 0", Instruction::from(String::from("zero a, b, c")).describe());
+    }
+
+    #[test]
+    fn check_validate_operands() {
+        let invalid = |line: &str| {
+            let mut program = Program::new(line);
+            program.parse();
+            program.validate_operands()
+        };
+
+        for line in ["inc 5", "dec 'A'", "mov 5, a", "add $steps, 1", "sub -1, a", "mul rip, 2", "div 'AB', 2", "adc 1, 1", "sbb 1, 1", "mulmod 3, a, b, c", "read 7",
+            "not 3", "neg 'A'", "abs -2", "pop 4", "xchg 1, a", "xchg a, 2", "shl 1, 2", "shr 8, a", "sar -8, 1", "mod 7, 2"] {
+            let diagnostics = invalid(line);
+            assert_eq!(1, diagnostics.len(), "{}", line);
+            assert_eq!(Severity::Error, diagnostics[0].severity);
+            assert_eq!(Some(1), diagnostics[0].line);
+        }
+
+        let diagnostics = invalid("mov a, 1\n  zero a, 3, b");
+        assert_eq!(1, diagnostics.len());
+        assert_eq!("`zero` needs a register or memory destination, found `3`", diagnostics[0].message);
+        assert_eq!(Some(2), diagnostics[0].line);
+        assert_eq!(Some(10..11), diagnostics[0].span);
+
+        assert!(invalid("mov rip, 1\nmov [a+1], 2\ninc b_2\nmsg 'text', 5\ncmp 1, 2").is_empty());
+    }
+
+    #[test]
+    fn check_cmp_label() {
+        // `handler:` sits at index 4, so it resolves to the instruction after it
+        let program = "mov a, 5\ncmp a, handler\nje handler\nend\nhandler:\nmsg 'dispatched'\nend\n";
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("dispatched"))), output);
+
+        let program = "mov a, 4\ncmp a, handler\nje handler\nend\nhandler:\nmsg 'dispatched'\nend\n";
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from(""))), output);
+
+        // Registers win over labels with the same name
+        let program = "mov handler, 4\ncmp handler, 5\nje handler\nend\nhandler:\nmsg 'dispatched'\nend\n";
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from(""))), output);
+    }
+
+    #[test]
+    fn check_stop_flag() {
+        let stop = AtomicBool::new(false);
+        let mut interpreter = Interpreter::new("msg 'running'\nloop:\ninc a\njmp loop\n").with_stop_flag(&stop);
+        for _ in 0..10 {
+            assert_eq!(None, interpreter.step().unwrap().termination);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(Ok(Termination::Interrupted(String::from("running"))), interpreter.run());
+        assert_eq!(9, interpreter.steps());
+        assert_eq!(Some(String::from("running")), Termination::Interrupted(String::from("running")).into_output());
+    }
+
+    #[test]
+    fn check_lahf_sahf() {
+        let program = "cmp 1, 2\nlahf a\ncmp 2, 2\nlahf b\nsahf a\njl below\nmsg 'not below'\nend\nbelow:\nmsg a, ' ', b\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("22 17"))), output);
+        assert_eq!((0, 1, 1), (interpreter.zf, interpreter.cf, interpreter.pf));
+
+        let program = "mov a, 19\nsahf a\nlahf b\nmsg b\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("19"))), output);
+        assert_eq!((1, 1, 0, 1), (interpreter.zf, interpreter.cf, interpreter.sf, interpreter.pf));
+
+        // `between` sets PF like `cmp` against the nearest bound: a distance of 0 inside the range, -2
+        // below it
+        let program = "between 5, 0, 9\nlahf a\nbetween 0, 2, 9\nlahf b\nmsg a, ' ', b\nend\n";
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("17 6"))), output);
+    }
+
+    #[test]
+    fn check_entry_points() {
+        let program = "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\nunused:\n    ret\n";
+        let interpreter = Interpreter::new(program);
+        assert_eq!(
+            vec![(String::from("proc_fact"), LabelKind::Entry), (String::from("print"), LabelKind::Entry)],
+            interpreter.program.entry_points()
+        );
+
+        let interpreter = Interpreter::new("call half\nend\nhalf:\nloop:\ndiv a, 2\njcxz a, done\njmp loop\ndone:\nret\n");
+        assert_eq!(
+            vec![
+                (String::from("half"), LabelKind::Entry),
+                (String::from("loop"), LabelKind::Internal),
+                (String::from("done"), LabelKind::Internal),
+            ],
+            interpreter.program.entry_points()
+        );
+
+        let interpreter = Interpreter::new("cmp a, 0\ncallne half\njmpeq done\nend\nhalf:\n    ret\ndone:\n    end\n");
+        assert_eq!(
+            vec![(String::from("half"), LabelKind::Entry), (String::from("done"), LabelKind::Internal)],
+            interpreter.program.entry_points()
+        );
+    }
+
+    #[test]
+    fn check_labels_at() {
+        let interpreter = Interpreter::new("jmp second\nfirst:\nsecond:\n\ninc a\nthird:\nend\n");
+        assert_eq!(vec!["first", "second"], interpreter.program.labels_at(4));
+        assert_eq!(vec!["third"], interpreter.program.labels_at(6));
+        assert!(interpreter.program.labels_at(0).is_empty());
+    }
+
+    #[test]
+    fn check_conditional_suffix() {
+        let program = "mov a, 1\ncmp a, 1\naddeq a, 10\nmovne b, 5\nmovle c, 7\nmsg a, ' ', b, ' ', c\nend\n";
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("11 0 7"))), output);
+
+        let program = "mov a, 1\ncmp a, 2\naddeq a, 10\nmovne b, 5\nmovgt c, 7\nmsg a, ' ', b, ' ', c\nend\n";
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("1 5 0"))), output);
+
+        let instruction = Instruction::from(String::from("movne a, b"));
+        assert_eq!(
+            Instruction::Cond(Condition::Ne, Box::new(Instruction::Mov(Symbol::from("a"), Symbol::from("b")))),
+            instruction
+        );
+        assert_eq!("movne a, b", instruction.to_string());
+        assert_eq!("if not equal: a = b", instruction.describe());
+        assert_eq!(vec!["a"], instruction.destinations());
+        assert_eq!(Instruction::Nop, Instruction::from(String::from("fooeq a")));
+    }
+
+    #[test]
+    fn check_runtime_interning() {
+        // Names only found inside a message condition are looked up while running, never interned
+        let program = "mov cell, 16\nmov [cell+8], 5\ncmp 1, 1\nmsg ?(only_in_condition) 'set' : [cell+only_in_address], ' ', [cell+8]\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("0 5"))), output);
+        assert_eq!(None, interpreter.program.names.lookup("only_in_condition"));
+        assert_eq!(None, interpreter.program.names.lookup("only_in_address"));
+
+        // Terms of memory operands are interned while parsing
+        let mut parsed = Program::new(program);
+        parsed.parse();
+        assert_eq!(vec![(false, Symbol::from("cell")), (false, Symbol::from("8"))], parsed.addresses[&Symbol::from("[cell+8]")]);
+        // Running added nothing to the names the program parsed
+        assert_eq!(parsed.names.len(), interpreter.program.names.len());
+    }
+
+    #[test]
+    fn check_dump_parse_tree() {
+        let interpreter = Interpreter::new("mov a, 5 ; five\n\nloop:\n  msg 'a = ', a\nbogus x\n");
+        assert_eq!(
+            concat!(
+                "   1 | mov a, 5 ; five\n     | tokens: [\"mov\", \"a\", \"5\"]\n     | Mov(\"a\", \"5\")\n",
+                "   2 | \n     | tokens: []\n     | Nop\n",
+                "   3 | loop:\n     | tokens: [\"loop:\"]\n     | Function(\"loop\")\n",
+                "   4 |   msg 'a = ', a\n     | tokens: [\"msg\", \"'a = '\", \"a\"]\n     | Msg([\"'a = '\", \"a\"])\n",
+                "   5 | bogus x\n     | tokens: [\"bogus\", \"x\"]\n     | Nop\n",
+            ),
+            interpreter.program.dump_parse_tree()
+        );
+    }
+
+    #[test]
+    fn check_call_expr() {
+        let program = "mov b, 7\ncall_expr a, square\nmsg b, '^2 = ', a\nend\n\nsquare:\n    mov c, b\n    mul c, b\n    push c\n    ret\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("7^2 = 49"))), output);
+        assert!(interpreter.values.is_empty());
+
+        // Plain calls leave pushed values alone
+        let (interpreter, _) = Interpreter::interpret("call square\nend\nsquare:\npush 4\nret\n");
+        assert_eq!(vec![4], interpreter.values);
+
+        let mut interpreter = Interpreter::new("call_expr a, nothing\nend\nnothing:\nret\n");
+        assert_eq!(Err(InterpretError::EmptyValueStack), interpreter.run());
+    }
+
+    #[test]
+    fn check_self_move() {
+        let mut interpreter = Interpreter::new("mov a, 3\nmov a, a\nmov [8], a\nmov [8], [8]\nend\n");
+        assert_eq!(vec![(String::from("a"), 3)], interpreter.step().unwrap().writes);
+        assert!(interpreter.step().unwrap().writes.is_empty());
+        assert_eq!(vec![(String::from("[8]"), 3)], interpreter.step().unwrap().writes);
+        assert!(interpreter.step().unwrap().writes.is_empty());
+        assert_eq!(Some(Termination::End(String::new())), interpreter.step().unwrap().termination);
+        assert_eq!(5, interpreter.steps());
+    }
+
+    #[test]
+    fn check_times() {
+        let (interpreter, output) = Interpreter::interpret("times 3 inc a\ntimes 2 nop\nloop:\nmsg a\nend\n");
+        assert_eq!(Ok(Some(String::from("3"))), output);
+        assert_eq!(8, interpreter.program.instructions.len());
+        assert_eq!(6, interpreter.program.symbols()[0].1);
+        assert_eq!(vec![1, 1, 1, 2, 2, 3, 4, 5], interpreter.program.lines);
+
+        let mut interpreter = Interpreter::new("mov a, 1\ntimes 1000000 inc a\nend\n");
+        let error = InterpretError::InvalidRepeatCount { count: String::from("1000000"), line: 2 };
+        assert_eq!(vec![error.clone()], interpreter.program.errors());
+        assert_eq!(Err(error), interpreter.run());
+        assert_eq!(0, interpreter.steps());
+    }
+
+    #[test]
+    fn check_width() {
+        let mut interpreter = Interpreter::new("mov a, 255\nadd a, 1\nend\n").with_width(Width::Bits8);
+        interpreter.run().unwrap();
+        assert_eq!(Some(&0), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!((1, 1), (interpreter.zf, interpreter.cf));
+
+        let mut interpreter = Interpreter::new("mov a, -1\nmov b, 70000\ninc b\nend\n").with_width(Width::Bits16);
+        interpreter.run().unwrap();
+        assert_eq!(Some(&0xffff), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(Some(&4465), interpreter.register.get(&Symbol::from("b")));
+        assert_eq!((0, 0), (interpreter.zf, interpreter.cf));
+
+        let mut interpreter = Interpreter::new("mov a, 9223372036854775807\ninc a\nend\n").with_width(Width::Bits64);
+        interpreter.run().unwrap();
+        assert_eq!(Some(&i64::MIN), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!((0, 1), (interpreter.zf, interpreter.cf));
+
+        assert_eq!(Some(Width::Bits32), Width::from_bits(32));
+        assert_eq!(32, Width::Bits32.bits());
+        for bits in [0, 1, 63, 65, 128] {
+            assert_eq!(None, Width::from_bits(bits));
+        }
+    }
+
+    #[test]
+    fn check_breakpoints() {
+        let program = "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n";
+        let mut interpreter = Interpreter::new(program);
+        assert_eq!(Ok(16), interpreter.set_breakpoint("print"));
+        assert_eq!(Err(InterpretError::UndefinedLabel(String::from("missing"))), interpreter.set_breakpoint("missing"));
+
+        assert_eq!(Ok(Termination::Breakpoint(16)), interpreter.run());
+        assert_eq!(Some(&120), interpreter.register.get(&Symbol::from("c")));
+        assert!(interpreter.out.is_empty());
+        assert_eq!(Ok(Termination::End(String::from("5! = 120"))), interpreter.run());
+
+        // Every pass through a breakpoint pauses
+        let mut interpreter = Interpreter::new(program);
+        interpreter.set_breakpoint_at(9);
+        assert_eq!(Ok(Termination::Breakpoint(9)), interpreter.run());
+        assert_eq!(Ok(Termination::Breakpoint(9)), interpreter.run());
+        assert!(interpreter.clear_breakpoint(9));
+        assert!(!interpreter.clear_breakpoint(9));
+        assert_eq!(Ok(Termination::End(String::from("5! = 120"))), interpreter.run());
+    }
+
+    #[test]
+    fn check_between() {
+        let program = "read a\nbetween a, 10, 20\njl below\njg above\nmsg 'in range'\nend\nbelow:\nmsg 'below'\nend\nabove:\nmsg 'above'\nend\n";
+        for (input, expected) in [(9, "below"), (10, "in range"), (20, "in range"), (21, "above")] {
+            let (_, output) = Interpreter::run_with_input(program, &[input]);
+            assert_eq!(Ok(Some(String::from(expected))), output);
+        }
+    }
+
+    #[test]
+    fn check_line_of() {
+        let interpreter = Interpreter::new("; header\ntimes 2 inc a\n\nmsg a\nend\n");
+        assert_eq!(Some(1), interpreter.program.line_of(0));
+        assert_eq!(Some(2), interpreter.program.line_of(2));
+        assert_eq!(Some(4), interpreter.program.line_of(4));
+        assert_eq!(None, interpreter.program.line_of(6));
+    }
+
+    #[test]
+    fn check_rounding() {
+        let program = "mov a, -7\ndiv a, 2\nmov b, 7\ndiv b, -2\nmov c, -8\ndiv c, 2\nmsg a, ' ', b, ' ', c\nend\n";
+
+        let (_, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("-3 -3 -4"))), output);
+
+        let mut interpreter = Interpreter::new(program).with_rounding(Rounding::FloorDivision);
+        assert_eq!(Ok(Termination::End(String::from("-4 -4 -4"))), interpreter.run());
+    }
+
+    #[test]
+    fn check_accumulator_forms() {
+        assert_eq!(Instruction::Mul(Symbol::from("a"), Symbol::from("3")), Instruction::from(String::from("mul 3")));
+
+        let (_, output) = Interpreter::interpret("mov a, 5\nmov b, 4\nmul 3\nmul b\ndiv 6\nmsg a, ' ', b\nend\n");
+        assert_eq!(Ok(Some(String::from("10 4"))), output);
+    }
+
+    #[test]
+    fn check_prelude() {
+        let (interpreter, output) = Interpreter::interpret("mov a, 81\nmov b, 153\ncall __gcd\nmsg 'gcd = ', a\nend\n");
+        assert_eq!(Ok(Some(String::from("gcd = 9"))), output);
+        assert_eq!(None, interpreter.program.line_of(5));
+
+        let (_, output) = Interpreter::interpret("mov a, 2\nmov b, 10\ncall __pow\nmov c, a\nmov a, -7\ncall __abs\nmsg c, ' ', a\nend\n");
+        assert_eq!(Ok(Some(String::from("1024 7"))), output);
+
+        // Missing `end` still falls through instead of running into the built-ins
+        let mut interpreter = Interpreter::new("mov a, -7\ncall __abs\n");
+        assert_eq!(Ok(Termination::FellThrough), interpreter.run());
+
+        // The program's own labels win, and the built-ins can be left out
+        let (_, output) = Interpreter::interpret("call __abs\nmsg a\nend\n__abs:\nmov a, 1\nret\n");
+        assert_eq!(Ok(Some(String::from("1"))), output);
+        let interpreter = Interpreter::from_program(Program::new("call __gcd\nend\n").without_prelude());
+        assert_eq!(2, interpreter.program.instructions.len());
+
+        // Programs that don't call them are left alone
+        assert_eq!(2, Interpreter::new("mov a, 1\nend\n").program.instructions.len());
+
+        // Names of the built-ins' own labels and scratch registers can't be used next to them
+        let program = "mov __r, 5\nmov a, 12\nmov b, 18\ncall __gcd\njmp __gcd_done\nmsg a, ' ', __r\nend\n__gcd_done:\nmov __r, 1\nend\n";
+        let reserved = |name: &str, line| InterpretError::ReservedName { name: String::from(name), line };
+        let interpreter = Interpreter::new(program);
+        assert_eq!([reserved("__r", 1), reserved("__gcd_done", 5)], interpreter.program.errors());
+        assert_eq!(Some(8), reserved("__r", 8).line());
+
+        // Without the built-ins such names are the program's own
+        let (_, output) = Interpreter::interpret("mov __r, 5\nmsg __r\nend\n");
+        assert_eq!(Ok(Some(String::from("5"))), output);
+    }
+
+    #[test]
+    fn check_metrics() {
+        let program = "mov a, 4\nmov r, 1\ncall fact\nmov [0], r\nmsg r\nend\n\nfact:\n    cmp a, 1\n    jle fact_done\n    mul r, a\n    dec a\n    call fact\nfact_done:\n    ret\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("24"))), output);
+        assert_eq!(
+            Metrics { steps: 27, cycles: 27, peak_stack_depth: 4, registers: 2, memory_bytes: 8 },
+            interpreter.metrics()
+        );
+    }
+
+    #[test]
+    fn check_encoding() {
+        let s = Symbol::from;
+        let instructions = vec![
+            Instruction::Mov(s("a"), s("5")),
+            Instruction::Xchg(s("a"), s("b")),
+            Instruction::Inc(s("a")),
+            Instruction::Dec(s("a")),
+            Instruction::Add(s("a"), s("b")),
+            Instruction::Sub(s("a"), s("b")),
+            Instruction::Mul(s("a"), s("b")),
+            Instruction::Div(s("a"), s("b")),
+            Instruction::Mod(s("a"), s("b")),
+            Instruction::Adc(s("a"), s("b")),
+            Instruction::Sbb(s("a"), s("b")),
+            Instruction::Mulmod(s("a"), s("b"), s("c"), s("m")),
+            Instruction::Zero(vec![s("a"), s("b"), s("c")]),
+            Instruction::Zero(vec![]),
+            Instruction::Lahf(s("f")),
+            Instruction::Sahf(s("f")),
+            Instruction::Function(s("loop")),
+            Instruction::Call(s("loop")),
+            Instruction::CallExpr(s("a"), s("loop")),
+            Instruction::Push(s("a")),
+            Instruction::Pop(s("a")),
+            Instruction::And(s("a"), s("3")),
+            Instruction::Or(s("a"), s("b")),
+            Instruction::Xor(s("a"), s("a")),
+            Instruction::Not(s("a")),
+            Instruction::Neg(s("a")),
+            Instruction::Abs(s("a")),
+            Instruction::Shl(s("a"), s("1")),
+            Instruction::Shr(s("a"), s("b")),
+            Instruction::Sar(s("a"), s("63")),
+            Instruction::Abort(vec![s("'failed '"), s("a")]),
+            Instruction::Cmp(s("a"), s("'A'")),
+            Instruction::Test(s("a"), s("a")),
+            Instruction::Cmpz(s("a")),
+            Instruction::Command(s("greet"), vec![s("a"), s("2")]),
+            Instruction::Between(s("a"), s("0"), s("9")),
+            Instruction::Jmp(s("loop")),
+            Instruction::Jne(s("loop")),
+            Instruction::Je(s("loop")),
+            Instruction::Jge(s("loop")),
+            Instruction::Jg(s("loop")),
+            Instruction::Jle(s("loop")),
+            Instruction::Jl(s("loop")),
+            Instruction::Jcxz(s("c"), s("loop")),
+            Instruction::Read(s("a")),
+            Instruction::Msg(vec![s("'a, b = '"), s("a"), s("', '"), s("b")]),
+            Instruction::Flush,
+            Instruction::Ret(0),
+            Instruction::Ret(2),
+            Instruction::End,
+            Instruction::Pad(u64::MAX - 1),
+            Instruction::Sleep(s("100")),
+            Instruction::Rand(s("r")),
+            Instruction::CtxSave(s("saved")),
+            Instruction::CtxLoad(s("saved")),
+            Instruction::CtxCopyPrefix(s("saved"), s("r")),
+            Instruction::Cond(Condition::Ne, Box::new(Instruction::Mov(s("a"), s("b")))),
+            Instruction::Cond(Condition::Le, Box::new(Instruction::Ret(0))),
+            Instruction::Nop,
+        ];
+        let mut names = SymbolTable::default();
+        for instruction in instructions {
+            let bytes = instruction.encode(&mut names);
+            assert_eq!(Some(&instruction), Instruction::decode(&bytes, &names).as_ref());
+        }
+        // Ids only mean something in the table they were given out by
+        assert_eq!(None, Instruction::decode(&Instruction::Inc(s("a")).encode(&mut names), &SymbolTable::default()));
+
+        assert_eq!(None, Instruction::decode(&[0xff; ENCODED_SIZE], &names));
+        let mut bytes = Instruction::Ret(0).encode(&mut names);
+        bytes[1] = 7;
+        assert_eq!(None, Instruction::decode(&bytes, &names));
+    }
+
+    #[test]
+    fn check_symbol_maps() {
+        // Hot loop over a factorial, looking up the same registers and labels over and over
+        let program = "mov n, 0\nloop:\nmov a, 1\nmov c, 10\nfact:\nmul a, c\ndec c\ncmp c, 1\njg fact\ninc n\ncmp n, 100\njl loop\nmsg a, ' ', n\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("3628800 100"))), output);
+        assert_eq!(4103, interpreter.steps());
+
+        // Many registers still resolve to their own values
+        let program = (0..200).map(|i| format!("mov r{}, {}\n", i, i)).collect::<String>()
+            + "mov s, 0\n"
+            + &(0..200).map(|i| format!("add s, r{}\n", i)).collect::<String>()
+            + "msg s\nend\n";
+        let (interpreter, output) = Interpreter::interpret(&program);
+        assert_eq!(Ok(Some(String::from("19900"))), output);
+        assert_eq!(201, interpreter.metrics().registers);
+    }
+
+    #[test]
+    fn check_sleep() {
+        let program = "mov t, 40\nsleep 100\nsleep t\nsleep -5\nmsg 'done'\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("done"))), output);
+        assert_eq!(140, interpreter.clock());
+
+        // The clock stops at its maximum instead of overflowing
+        let (interpreter, output) = Interpreter::interpret("times 3 sleep 9223372036854775807\nend\n");
+        assert_eq!(Ok(Some(String::new())), output);
+        assert_eq!(u64::MAX, interpreter.clock());
+    }
+
+    #[test]
+    fn check_runtime_errors() {
+        let (_, output) = Interpreter::interpret("jmp nowhere\nend\n");
+        assert_eq!(Err(InterpretError::UndefinedLabel(String::from("nowhere"))), output);
+
+        let (_, output) = Interpreter::interpret("mov a, 1\ncmp a, 1\nje missing\nend\n");
+        assert_eq!(Err(InterpretError::UndefinedLabel(String::from("missing"))), output);
+
+        let (_, output) = Interpreter::interpret("call missing\nend\n");
+        assert_eq!(Err(InterpretError::UndefinedLabel(String::from("missing"))), output);
+
+        let (interpreter, output) = Interpreter::interpret("mov a, 1\nret\n");
+        assert_eq!(Err(InterpretError::StackUnderflow { line: 2 }), output);
+        assert_eq!(1, interpreter.register[&Symbol::from("a")]);
+
+        // Jumping into a function instead of calling it leaves nothing to return to
+        let (_, output) = Interpreter::interpret("mov a, 1
+jmp helper
+end
+helper:
+    inc a
+    ret
+");
+        assert_eq!(Err(InterpretError::StackUnderflow { line: 6 }), output);
+        assert_eq!(Some(6), output.unwrap_err().line());
+
+        let (_, output) = Interpreter::interpret("mov a, 1\n\nmov b, 0\ndiv a, b\nend\n");
+        assert_eq!(Err(InterpretError::DivisionByZero { register: String::from("a"), line: 4 }), output);
+        assert_eq!(Some(4), output.unwrap_err().line());
+    }
+
+    #[test]
+    fn check_rand() {
+        let program = "rand a\nrand b\nmsg a, ' ', b\nend\n";
+        let output = |seed| Interpreter::new(program).with_seed(seed).run().unwrap().into_output();
+        assert_eq!(output(7), output(7));
+        assert_ne!(output(7), output(8));
+
+        let mut interpreter = Interpreter::new(program).with_seed(7);
+        interpreter.run().unwrap();
+        assert!(interpreter.register[&Symbol::from("a")] >= 0);
+        assert_ne!(interpreter.register[&Symbol::from("a")], interpreter.register[&Symbol::from("b")]);
+    }
+
+    #[test]
+    fn check_undefined_labels() {
+        let program = "jmp start\nstart:\ncall helper\ncmp a, 1\njne retry\njmpeq elsewhere\ncall helper\nend\n";
+        let program = Program::new(program);
+        let mut interpreter = Interpreter::from_program(program);
+        assert_eq!(
+            vec![
+                InterpretError::UndefinedLabel(String::from("helper")),
+                InterpretError::UndefinedLabel(String::from("retry")),
+                InterpretError::UndefinedLabel(String::from("elsewhere")),
+            ],
+            interpreter.program.errors()
+        );
+        // Nothing runs, not even the instructions before the first bad jump
+        assert_eq!(Err(InterpretError::UndefinedLabel(String::from("helper"))), interpreter.run());
+        assert_eq!(0, interpreter.steps());
+    }
+
+    #[test]
+    fn check_mnemonic_in_operands() {
+        assert_eq!(
+            Instruction::Mov(Symbol::from("mov_count"), Symbol::from("5")),
+            Instruction::from(String::from("mov mov_count, 5"))
+        );
+        assert_eq!(Instruction::Inc(Symbol::from("incr")), Instruction::from(String::from("inc\tincr")));
+        assert_eq!(Instruction::Msg(vec![Symbol::from("'add them'")]), Instruction::from(String::from("msg 'add them'")));
+
+        let program = "mov mov_count, 5\nadd add, mov_count\nmsg 'msg add ', add\nend\n";
+        assert_eq!(Ok(Some(String::from("msg add 5"))), Interpreter::interpret(program).1);
+    }
+
+    #[test]
+    fn check_constants() {
+        let program = "SIZE equ 8\nLETTER equ 'A'\nBASE equ SIZE\nmov a, 2\nmul a, SIZE\nmov [BASE+SIZE], LETTER\nmov b, [16]\ncmp a, 16\nje ok\nend\nok:\nmsg 'size ', SIZE, ' a ', a, ' b ', b\nend\n";
+        assert_eq!(Ok(Some(String::from("size 8 a 16 b 65"))), Interpreter::interpret(program).1);
+
+        // A constant is read instead of a register or label of the same name, and can't be written
+        let program = "n equ 3\nn:\ncmp n, 3\nje done\nend\ndone:\nmsg n\nend\n";
+        assert_eq!(Ok(Some(String::from("3"))), Interpreter::interpret(program).1);
+        let program = Program::new("n equ 3\nmov n, 4\nend\n");
+        let mut interpreter = Interpreter::from_program(program);
+        assert_eq!(1, interpreter.program.validate_operands().len());
+        // The library rejects it as well, not only the command line
+        let error = InterpretError::NotWritable { mnemonic: String::from("mov"), operand: String::from("n"), line: 2 };
+        assert_eq!(Err(error), interpreter.run());
+        assert_eq!(1, interpreter.program.diagnostics().len());
+
+        let program = Program::new("n equ x\nend\n");
+        let errors = Interpreter::from_program(program).program.errors().to_vec();
+        assert_eq!(vec![InterpretError::InvalidConstant { value: String::from("x"), line: 1 }], errors);
+    }
+
+    #[test]
+    fn check_ret_cleanup() {
+        // The callee pushes its result and drops the two arguments below it on return
+        let program = "push 7\npush 100\npush 3\ncall_expr a, f\nmsg a\nend\nf:\npush 0\nret 2\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("0"))), output);
+        assert_eq!(vec![7], interpreter.values);
+
+        let program = "push 1\ncall f\nend\nf:\nret 2\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Err(InterpretError::EmptyValueStack), output);
+        // Nothing was popped, the failed `ret` is still inside `f`
+        assert_eq!((vec![2], vec![1], 4), (interpreter.stack.clone(), interpreter.values.clone(), interpreter.rip));
+        assert_eq!("ret 2", Instruction::from(String::from("ret 2")).to_string());
+
+        let program = "push 7\ncall_expr a, f\nend\nf:\nret 1\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Err(InterpretError::EmptyValueStack), output);
+        assert_eq!((vec![2], vec![7]), (interpreter.stack.clone(), interpreter.values.clone()));
+
+        let invalid = |literal: &str, line| InterpretError::InvalidLiteral { literal: literal.to_string(), line };
+        let interpreter = Interpreter::new("call f\nend\nf:\nret x\nretne -1\nret 0x2\n");
+        assert_eq!([invalid("x", 4), invalid("-1", 5)], interpreter.program.errors());
+    }
+
+    #[test]
+    fn check_quoted_semicolon() {
+        assert_eq!("msg 'a ; b', x", clean_line("  msg 'a ; b', x ; print it"));
+        assert_eq!("mov a, 1", clean_line("mov a, 1; 'quoted' ; comment"));
+
+        let program = "mov x, 2\nmsg 'a ; b = ', x ; print it\nend\n";
+        assert_eq!(Ok(Some(String::from("a ; b = 2"))), Interpreter::interpret(program).1);
+    }
+
+    #[test]
+    fn check_push_pop() {
+        // Values live apart from the return addresses, so they survive calls in between
+        let program = "mov a, 1\nmov b, 2\npush a\npush b\ncall swap\npop a\npop b\nmsg a, ' ', b\nend\nswap:\nret\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("2 1"))), output);
+        assert!(interpreter.values.is_empty());
+        assert!(interpreter.stack.is_empty());
+
+        assert_eq!(Err(InterpretError::EmptyValueStack), Interpreter::interpret("pop a\nend\n").1);
+    }
+
+    #[test]
+    fn check_abort() {
+        let program = "read a\ncmp a, 0\njge ok\nabort 'bad input ', a\nok:\nmsg a\nend\n";
+        let (_, output) = Interpreter::run_with_input(program, &[-4]);
+        assert_eq!(Ok(None), output);
+        let mut interpreter = Interpreter::new(program).with_input(&[-4]);
+        assert_eq!(Ok(Termination::Aborted(String::from("bad input -4"))), interpreter.run());
+        let mut interpreter = Interpreter::new(program).with_input(&[4]);
+        assert_eq!(Ok(Termination::End(String::from("4"))), interpreter.run());
+    }
+
+    #[test]
+    fn check_strictness() {
+        let run = |program: &str, strictness| Interpreter::new(program).with_strictness(strictness).run().map(Termination::into_output);
+        let output = |text: &str| Ok(Some(String::from(text)));
+        let overflow = |register: &str| Err(InterpretError::Overflow { register: String::from(register), line: 2 });
+
+        let divide_by_zero = "mov a, 7\ndiv a, 0\nmulmod b, 2, 3, 0\nmsg a, ' ', b\nend\n";
+        assert_eq!(output("0 0"), run(divide_by_zero, StrictnessPolicy::Lenient));
+        let error = Err(InterpretError::DivisionByZero { register: String::from("a"), line: 2 });
+        assert_eq!(error, run(divide_by_zero, StrictnessPolicy::Hardware));
+        assert_eq!(error, run(divide_by_zero, StrictnessPolicy::Checked));
+
+        let min_by_minus_one = "mov a, -9223372036854775808\ndiv a, -1\nmsg a\nend\n";
+        assert_eq!(output("-9223372036854775808"), run(min_by_minus_one, StrictnessPolicy::Lenient));
+        assert_eq!(overflow("a"), run(min_by_minus_one, StrictnessPolicy::Hardware));
+        assert_eq!(overflow("a"), run(min_by_minus_one, StrictnessPolicy::Checked));
+
+        let neg_min = "mov a, -9223372036854775808\nneg a\nmsg a\nend\n";
+        assert_eq!(output("-9223372036854775808"), run(neg_min, StrictnessPolicy::Lenient));
+        assert_eq!(overflow("a"), run(neg_min, StrictnessPolicy::Hardware));
+        assert_eq!(overflow("a"), run(neg_min, StrictnessPolicy::Checked));
+
+        // Arithmetic that doesn't fit fails under the checked policy, whatever the arithmetic mode, see
+        // `check_overflow_policies`
+        let add_overflow = "mov a, 9223372036854775807\ninc a\nmsg a\nend\n";
+        assert_eq!(output("-9223372036854775808"), run(add_overflow, StrictnessPolicy::Hardware));
+        assert_eq!(overflow("a"), run(add_overflow, StrictnessPolicy::Checked));
+    }
+
+    #[test]
+    fn check_overflow_policies() {
+        let strictnesses = [StrictnessPolicy::Lenient, StrictnessPolicy::Hardware, StrictnessPolicy::Checked];
+        let modes = [ArithmeticMode::Wrapping, ArithmeticMode::Saturating, ArithmeticMode::Checked];
+        let output = |text: &str| Ok(Some(String::from(text)));
+        let overflow = Err(InterpretError::Overflow { register: String::from("a"), line: 2 });
+        let division = Err(InterpretError::DivisionByZero { register: String::from("a"), line: 2 });
+        let shift = Err(InterpretError::InvalidShift { count: 65, line: 2 });
+
+        for strictness in strictnesses {
+            for mode in modes {
+                let run = |program: &str, width| {
+                    let interpreter = Interpreter::new(program).with_strictness(strictness).with_arithmetic(mode);
+                    match width {
+                        Some(width) => interpreter.with_width(width),
+                        None => interpreter,
+                    }.run().map(Termination::into_output)
+                };
+
+                // The mode decides about results that don't fit, in 64 bits or a narrower width, unless
+                // the checked policy makes them fail
+                let expected = match (strictness, mode) {
+                    (StrictnessPolicy::Checked, _) | (_, ArithmeticMode::Checked) => overflow.clone(),
+                    (_, ArithmeticMode::Wrapping) => output("-9223372036854775808"),
+                    (_, ArithmeticMode::Saturating) => output("9223372036854775807"),
+                };
+                assert_eq!(expected, run("mov a, 9223372036854775807\nadd a, 1\nmsg a\nend\n", None));
+                let expected = match (strictness, mode) {
+                    (StrictnessPolicy::Checked, _) | (_, ArithmeticMode::Checked) => overflow.clone(),
+                    (_, ArithmeticMode::Wrapping) => output("4"),
+                    (_, ArithmeticMode::Saturating) => output("255"),
+                };
+                assert_eq!(expected, run("mov a, 250\nadd a, 10\nmsg a\nend\n", Some(Width::Bits8)));
+
+                // Only the policy decides about dividing by zero, negating `i64::MIN` and shift counts
+                let expected = match strictness {
+                    StrictnessPolicy::Lenient => output("0"),
+                    _ => division.clone(),
+                };
+                assert_eq!(expected, run("mov a, 7\ndiv a, 0\nmsg a\nend\n", None));
+                let expected = match strictness {
+                    StrictnessPolicy::Lenient => output("-9223372036854775808"),
+                    _ => overflow.clone(),
+                };
+                assert_eq!(expected, run("mov a, -9223372036854775808\nabs a\nmsg a\nend\n", None));
+                let expected = match strictness {
+                    StrictnessPolicy::Lenient => output("0"),
+                    StrictnessPolicy::Hardware => output("6"),
+                    StrictnessPolicy::Checked => shift.clone(),
+                };
+                assert_eq!(expected, run("mov a, 3\nshl a, 65\nmsg a\nend\n", None));
+            }
+        }
+    }
+
+    #[test]
+    fn check_bitwise() {
+        let program = "mov a, 6\nand a, 3\nmov b, 6\nor b, 9\nmov c, 6\nxor c, 3\nmov d, 0\nnot d\nxor a, a\nmsg a, ' ', b, ' ', c, ' ', d\nend\n";
+        assert_eq!(Ok(Some(String::from("0 15 5 -1"))), Interpreter::interpret(program).1);
+        let (interpreter, _) = Interpreter::interpret("mov a, 6\nand a, 3\nend\n");
+        assert_eq!(2, interpreter.register[&Symbol::from("a")]);
+
+        // Narrow registers keep only their low bits
+        let mut interpreter = Interpreter::new("mov a, 0\nnot a\nmsg a\nend\n").with_width(Width::Bits8);
+        assert_eq!(Ok(Termination::End(String::from("255"))), interpreter.run());
+    }
+
+    #[test]
+    fn check_shifts() {
+        let program = "mov a, 3\nshl a, 4\nmov b, -16\nmov c, b\nsar b, 2\nshr c, 60\nmsg a, ' ', b, ' ', c\nend\n";
+        assert_eq!(Ok(Some(String::from("48 -4 15"))), Interpreter::interpret(program).1);
+
+        // Shifting by 64 or more
+        let program = "mov a, 3\nmov b, -16\nmov c, -16\nshl a, 65\nsar b, 65\nshr c, 65\nmsg a, ' ', b, ' ', c\nend\n";
+        let run = |strictness| Interpreter::new(program).with_strictness(strictness).run().map(Termination::into_output);
+        assert_eq!(Ok(Some(String::from("0 -1 0"))), run(StrictnessPolicy::Lenient));
+        assert_eq!(Ok(Some(format!("6 -8 {}", (-16i64 as u64 >> 1) as i64))), run(StrictnessPolicy::Hardware));
+        assert_eq!(Err(InterpretError::InvalidShift { count: 65, line: 4 }), run(StrictnessPolicy::Checked));
+    }
+
+    #[test]
+    fn check_line_operand() {
+        let program = "; header\nmov a, 1\n\ntimes 2 inc a\nmov b, $line\nmsg 'at line ', $line, ' after line ', b\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("at line 6 after line 5"))), output);
+        assert_eq!(3, interpreter.register[&Symbol::from("a")]);
+    }
+
+    #[test]
+    fn check_continue_from() {
+        let program = "mov a, 10\nmov r, 1\ncall fact\nmov [8], r\nrand x\nmsg r, ' ', [8]\nend\n\nfact:\n    cmp a, 1\n    jle fact_done\n    mul r, a\n    dec a\n    call fact\nfact_done:\n    ret\n";
+        let mut uninterrupted = Interpreter::new(program).with_seed(3);
+        let output = uninterrupted.run();
+        assert_eq!(Ok(Termination::End(String::from("3628800 3628800"))), output);
+
+        // Pause in the middle of the recursion and save the state as text
+        let mut paused = Interpreter::new(program).with_seed(3);
+        for _ in 0..20 {
+            paused.step().unwrap();
+        }
+        assert_eq!(4, paused.stack.len());
+        let saved = paused.state().to_json();
+        drop(paused);
+
+        let state = State::from_json(&saved).unwrap();
+        let mut resumed = Interpreter::continue_from(Program::new(program), state.clone()).unwrap();
+        assert_eq!(output, resumed.run());
+        assert_eq!(uninterrupted.state(), resumed.state());
+
+        let other = Program::new("mov a, 1\nend\n");
+        assert_eq!(Some(InterpretError::StateMismatch), Interpreter::continue_from(other, state).err());
+
+        // Values that don't fit are rejected instead of truncated, and so is a newer format
+        let edited = |key: &str, edit: serde_json::Value| {
+            let mut json: serde_json::Value = serde_json::from_str(&saved).unwrap();
+            json[key] = edit;
+            State::from_json(&json.to_string())
+        };
+        assert_eq!(None, edited("zf", serde_json::json!(256)));
+        assert_eq!(None, edited("cf", serde_json::json!(2)));
+        assert_eq!(None, edited("memory", serde_json::json!([[8, 300]])));
+        assert_eq!(None, edited("random", serde_json::json!(0)));
+        assert_eq!(None, edited("version", serde_json::json!(2)));
+        assert_eq!(Some(1), edited("memory", serde_json::json!([[8, 255]])).map(|state| state.memory.len()));
+        let mut json: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        json.as_object_mut().unwrap().remove("version");
+        assert_eq!(State::from_json(&saved), State::from_json(&json.to_string()));
+    }
+
+    #[test]
+    fn check_mod() {
+        assert_eq!(Ok(Some(String::from("2"))), Interpreter::interpret("mov a, 17\nmod a, 5\nmsg a\nend\n").1);
+        assert_eq!(Ok(Some(String::from("-2 3"))), Interpreter::interpret("mov a, -17\nmov b, 17\nmod a, 5\nmod b, -7\nmsg a, ' ', b\nend\n").1);
+
+        let program = "mov a, -17\nmod a, 5\nmsg a\nend\n";
+        let floored = Interpreter::new(program).with_rounding(Rounding::FloorDivision).run().map(Termination::into_output);
+        assert_eq!(Ok(Some(String::from("3"))), floored);
+
+        assert_eq!(
+            Err(InterpretError::DivisionByZero { register: String::from("a"), line: 2 }),
+            Interpreter::interpret("mov a, 17\nmod a, 0\nend\n").1
+        );
+    }
+
+    #[test]
+    fn check_neg_abs() {
+        let program = "mov a, 5\nmov b, -7\nmov c, 0\nneg a\nneg b\nneg c\nmov d, a\nmov e, b\nabs c\nabs d\nabs e\nmsg a, ' ', b, ' ', c, ' ', d, ' ', e\nend\n";
+        assert_eq!(Ok(Some(String::from("-5 7 0 5 7"))), Interpreter::interpret(program).1);
+
+        let program = "mov a, -9223372036854775808\nabs a\nmsg a\nend\n";
+        let run = |strictness| Interpreter::new(program).with_strictness(strictness).run().map(Termination::into_output);
+        assert_eq!(Ok(Some(i64::MIN.to_string())), run(StrictnessPolicy::Lenient));
+        assert_eq!(Err(InterpretError::Overflow { register: String::from("a"), line: 2 }), run(StrictnessPolicy::Hardware));
+        assert_eq!(
+            Err(InterpretError::Overflow { register: String::from("a"), line: 2 }),
+            Interpreter::interpret("mov a, -9223372036854775808\nneg a\nend\n").1
+        );
+    }
+
+    #[test]
+    fn check_conditional_message() {
+        let program = "mov a, 4\nmov b, 7\ncmp a, b\nmsg 'result: ', ?(zf) 'equal' : 'not: equal', ' ', ?(a) a : 'none', ?(zf) '!'\nend\n";
+        assert_eq!(Ok(Some(String::from("result: not: equal 4"))), Interpreter::interpret(program).1);
+
+        let program = program.replacen("mov b, 7", "mov b, 4", 1);
+        assert_eq!(Ok(Some(String::from("result: equal 4!"))), Interpreter::interpret(&program).1);
+    }
+
+    #[test]
+    fn check_xchg() {
+        let program = "mov a, 1\nmov b, 2\nxchg a, b\nxchg a, c\nmsg a, ' ', b, ' ', c\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("0 1 2"))), output);
+        assert_eq!(Some(&2), interpreter.register.get(&Symbol::from("c")));
+    }
+
+    #[test]
+    fn check_sign_overflow_flags() {
+        let run = |program: &str| {
+            let (interpreter, _) = Interpreter::interpret(program);
+            (interpreter.sf, interpreter.of)
+        };
+        assert_eq!((0, 0), run("cmp 5, 3\nend\n"));
+        assert_eq!((1, 0), run("cmp 3, 5\nend\n"));
+        assert_eq!((0, 0), run("cmp 5, 5\nend\n"));
+        // i64::MIN - 1 wraps around to the largest positive value
+        assert_eq!((0, 1), run("mov a, -9223372036854775808\ncmp a, 1\nend\n"));
+        assert_eq!((1, 1), run("mov a, 9223372036854775807\ncmp a, -1\nend\n"));
+
+        let (interpreter, _) = Interpreter::interpret("cmp 3, 5\nend\n");
+        assert!(interpreter.to_string().contains("SF: 1"));
+        assert!(interpreter.to_string().contains("OF: 0"));
+    }
+
+    #[test]
+    fn check_signed_jumps() {
+        let branch = |lhs: i64, rhs: i64, jump: &str| {
+            let program = format!("mov a, {}\nmov b, {}\ncmp a, b\n{} taken\nmsg 'not taken'\nend\ntaken:\n    msg 'taken'\n    end\n", lhs, rhs, jump);
+            let output = Interpreter::interpret(&program).1;
+            output.unwrap().unwrap()
+        };
+        // -5 is below 3 although its unsigned borrow says otherwise
+        assert_eq!("taken", branch(-5, 3, "jl"));
+        assert_eq!("not taken", branch(-5, 3, "jge"));
+        assert_eq!("taken", branch(3, -5, "jg"));
+        assert_eq!("not taken", branch(3, -5, "jle"));
+        // The subtraction overflows here, so its sign alone would give the wrong answer
+        assert_eq!("taken", branch(i64::MIN, 1, "jl"));
+        assert_eq!("taken", branch(i64::MAX, -1, "jg"));
+        assert_eq!("not taken", branch(i64::MAX, -1, "jle"));
+        assert_eq!("taken", branch(7, 7, "jle"));
+        assert_eq!("taken", branch(7, 7, "jge"));
+
+        let (interpreter, _) = Interpreter::interpret("cmp -5, 3\nend\n");
+        assert_eq!((0, 0, 1, 0), (interpreter.zf, interpreter.cf, interpreter.sf, interpreter.of));
+    }
+
+    #[test]
+    fn check_test() {
+        let program = "mov a, 0\ntest a, a\njne nonzero\nmsg 'zero ', a\nend\nnonzero:\n    msg 'nonzero ', a\n    end\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("zero 0"))), output);
+        assert_eq!((1, 0, 1), (interpreter.zf, interpreter.sf, interpreter.pf));
+
+        let program = program.replacen("mov a, 0", "mov a, -7", 1);
+        let (interpreter, output) = Interpreter::interpret(&program);
+        assert_eq!(Ok(Some(String::from("nonzero -7"))), output);
+        // -7 ends in the byte 0xf9, which has six bits set
+        assert_eq!((0, 1, 1), (interpreter.zf, interpreter.sf, interpreter.pf));
+        assert!(interpreter.to_string().contains("PF: 1"));
+
+        let (interpreter, _) = Interpreter::interpret("mov a, 6\ntest a, 3\nend\n");
+        assert_eq!((0, 0, 0), (interpreter.zf, interpreter.sf, interpreter.pf));
+        assert_eq!(Some(&6), interpreter.register.get(&Symbol::from("a")));
+    }
+
+    #[test]
+    fn check_macros() {
+        let program = "macro add3 %x { inc %x | inc %x | inc %x }\nmacro add6 %x { add3 %x | add3 %x }\nmov a, 1\nadd3 a\nadd6 b\nmsg a, ' ', b\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("4 6"))), output);
+        assert_eq!(vec![1, 2, 3, 4, 4, 4, 5, 5, 5, 5, 5, 5, 6, 7], interpreter.program.lines);
+
+        // Labels inside a macro are renamed per expansion, a label of the program with the same name is left alone
+        let program = "macro countdown %r, %n { mov %r, %n | loop: | dec %r | jcxz %r, done | jmp loop | done: }\ncountdown a, 3\ncountdown b, 5\njmp done\nloop:\nmsg 'skipped'\ndone:\nmsg 'loop: %r ', a, ' ', b\nend\n";
+        assert_eq!(Ok(Some(String::from("loop: %r 0 0"))), Interpreter::interpret(program).1);
+
+        let program = "macro twice %x { inc %x | twice %x }\nmov a, 1\ntwice a\nend\n";
+        let error = InterpretError::RecursiveMacro { name: String::from("twice"), line: 3 };
+        assert_eq!(vec![error], Interpreter::new(program).program.errors());
+
+        let program = "macro add3 %x { inc %x | inc %x | inc %x }\nadd3 a, b\nend\n";
+        let error = InterpretError::MacroArguments { name: String::from("add3"), expected: 1, found: 2, line: 2 };
+        assert_eq!(vec![error], Interpreter::new(program).program.errors());
+
+        // Each macro doubling the last one would expand into 2^40 instructions
+        let mut program = String::from("macro m0 %x { inc %x }\n");
+        for n in 1..=40 {
+            program += &format!("macro m{} %x {{ m{} %x | m{} %x }}\n", n, n - 1, n - 1);
+        }
+        program += "m10 a\nm40 b\ntimes 1000 m4 c\nend\n";
+        let error = |line| InterpretError::ExpansionTooLarge { limit: MAX_EXPANDED, line };
+        assert_eq!(vec![error(43), error(44)], Interpreter::new(&program).program.errors());
+    }
+
+    #[test]
+    fn check_radix_literals() {
+        let program = "mov a, 0x10\nmov b, 0b101\nadd a, 0XfF\nmov c, -0x8000000000000000\nmov d, -12\nmsg a, ' ', b, ' ', c, ' ', d\nend\n";
+        assert_eq!(Ok(Some(format!("271 5 {} -12", i64::MIN))), Interpreter::interpret(program).1);
+
+        let program = "MASK equ 0b1111\nmov a, 0x1234\nand a, MASK\nmsg a\nend\n";
+        assert_eq!(Ok(Some(String::from("4"))), Interpreter::interpret(program).1);
+
+        assert_eq!(Some(16), parse_integer("0x10"));
+        assert_eq!(Some(5), parse_integer("0b101"));
+        assert_eq!(None, parse_integer("0x-10"));
+        assert_eq!(None, parse_integer("0b102"));
+        assert_eq!(None, parse_integer("0x8000000000000000"));
+    }
+
+    #[test]
+    fn check_digit_separators() {
+        let program = "mov a, 1_000_000\nmov b, 0xFF_FF\nmov c, -0b1_0000\nmov big_value, 2_5\nadd a, big_value\nmsg a, ' ', b, ' ', c\nend\n";
+        assert_eq!(Ok(Some(String::from("1000025 65535 -16"))), Interpreter::interpret(program).1);
+
+        let program = "LIMIT equ 10_000\nmov a, LIMIT\nmsg a\nend\n";
+        assert_eq!(Ok(Some(String::from("10000"))), Interpreter::interpret(program).1);
+
+        assert_eq!(Some(-1_000), parse_integer("-1_000"));
+        assert_eq!(Some(0xabcd), parse_integer("0x_ab_cd"));
+        assert_eq!(None, parse_integer("_1000"));
+        assert_eq!(None, parse_integer("1_000_x"));
+    }
+
+    #[test]
+    fn check_malformed_literals() {
+        let invalid = |literal: &str, line| InterpretError::InvalidLiteral { literal: literal.to_string(), line };
+        let interpreter = Interpreter::new("mov a, 1\nmov a, 0xZZ\nadd a, 99999999999999999999\nmov b, [a + 1_x]\nmsg a, -0b2\nend\n");
+        assert_eq!([invalid("0xZZ", 2), invalid("99999999999999999999", 3), invalid("1_x", 4), invalid("-0b2", 5)], interpreter.program.errors());
+
+        let (_, output) = Interpreter::interpret("mov a, 0xZZ\nmsg a\nend\n");
+        assert_eq!(Err(invalid("0xZZ", 1)), output);
+        assert_eq!("invalid literal `0xZZ`: expected a decimal, `0x` hexadecimal or `0b` binary number that fits the operand", invalid("0xZZ", 1).to_string());
+    }
+
+    #[test]
+    fn check_endianness() {
+        let program = "mov [16], 0x0102\nmov a, [16]\nmsg a\nend\n";
+        let bytes = |interpreter: &Interpreter| (16..24).map(|address| interpreter.memory[&address]).collect::<Vec<u8>>();
+
+        let mut little = Interpreter::new(program);
+        assert_eq!(Ok(Termination::End(String::from("258"))), little.run());
+        assert_eq!(vec![2, 1, 0, 0, 0, 0, 0, 0], bytes(&little));
+
+        let mut big = Interpreter::new(program).with_endianness(Endianness::Big);
+        assert_eq!(Ok(Termination::End(String::from("258"))), big.run());
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 1, 2], bytes(&big));
+
+        // An access 7 bytes further reads the lowest byte stored above as its highest or lowest byte
+        let program = "mov [16], 0x0102\nmov a, [23]\nmsg a\nend\n";
+        assert_eq!(Ok(Some(String::from("0"))), Interpreter::interpret(program).1);
+        let big = Interpreter::new(program).with_endianness(Endianness::Big).run().map(Termination::into_output);
+        assert_eq!(Ok(Some(String::from("144115188075855872"))), big);
+    }
+
+    #[test]
+    fn check_cmpz() {
+        let program = "mov a, 0\ncmpz a\nje zero\nmsg 'nonzero'\nend\nzero:\n    msg 'zero'\n    end\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("zero"))), output);
+        assert_eq!((1, 0), (interpreter.zf, interpreter.sf));
+
+        let program = program.replacen("mov a, 0", "mov a, -3", 1);
+        let (interpreter, output) = Interpreter::interpret(&program);
+        assert_eq!(Ok(Some(String::from("nonzero"))), output);
+        assert_eq!((0, 1), (interpreter.zf, interpreter.sf));
+
+        // With a register width, `jnz` sees the result of the last arithmetic without a `cmp`
+        let program = "mov a, 3\nloop:\n    dec a\n    jnz loop\nmsg 'a = ', a\nend\n";
+        let output = Interpreter::new(program).with_width(Width::Bits64).run().map(Termination::into_output);
+        assert_eq!(Ok(Some(String::from("a = 0"))), output);
+    }
+
+    #[test]
+    fn check_strict_parsing() {
+        let source = "mvo a, 5\nmsg 'a = ', a\nfooeq a\nend\n";
+        let output = Interpreter::interpret(source).1;
+        assert_eq!(Ok(Some(String::from("a = 0"))), output);
+
+        let mut program = Program::new(source).with_strict_parsing();
+        program.parse();
+        assert_eq!(
+            [
+                InterpretError::UnknownInstruction { mnemonic: String::from("mvo"), line: 1 },
+                InterpretError::UnknownInstruction { mnemonic: String::from("fooeq"), line: 3 },
+            ],
+            program.errors()
+        );
+
+        let mut interpreter = Interpreter::from_program(Program::new(source).with_strict_parsing());
+        assert_eq!(Err(InterpretError::UnknownInstruction { mnemonic: String::from("mvo"), line: 1 }), interpreter.run());
+    }
+
+    #[test]
+    fn check_operand_counts() {
+        let errors = |source: &str| {
+            let mut program = Program::new(source);
+            program.parse();
+            program.errors().to_vec()
+        };
+        let bad = |mnemonic: &str, expected: RangeInclusive<usize>, found, line| {
+            InterpretError::BadOperands { mnemonic: String::from(mnemonic), expected, found, line }
+        };
+
+        assert!(errors("call done\nend\ndone:\n    ret\n").is_empty());
+        assert_eq!(vec![bad("ret", 0..=1, 2, 1)], errors("ret 1, 2\n"));
+        assert_eq!(vec![bad("inc", 1..=1, 0, 2)], errors("mov a, 1\ninc\n"));
+        assert_eq!(vec![bad("mov", 2..=2, 1, 1), bad("add", 2..=2, 0, 2)], errors("mov a\nadd\nend\n"));
+        assert_eq!(vec![bad("movne", 2..=2, 1, 1)], errors("movne a,\nend\n"));
+
+        // Operands are taken by position, a left out one isn't skipped
+        assert_eq!(vec![bad("mov", 2..=2, 2, 1)], errors("mov a, , b\nend\n"));
+        assert_eq!(vec![bad("add", 2..=2, 1, 2)], errors("mov a, 1\nadd , 3\nend\n"));
+        assert_eq!(vec![bad("mul", 1..=2, 1, 1)], errors("mul a,\nend\n"));
+        assert_eq!(vec![bad("zero", 1..=usize::MAX, 2, 1)], errors("zero a, , b\nend\n"));
+        assert!(errors("msg 'a,,b', a\nend\n").is_empty());
+        let mut interpreter = Interpreter::new("mov a, , b\nend\n");
+        assert_eq!(Err(bad("mov", 2..=2, 2, 1)), interpreter.run());
+
+        let mut interpreter = Interpreter::new("mov a\nend\n");
+        assert_eq!(Err(bad("mov", 2..=2, 1, 1)), interpreter.run());
+        assert_eq!("`mov` takes 2 operands, found 1", bad("mov", 2..=2, 1, 1).to_string());
+        assert_eq!("`mul` takes 1 to 2 operands, found 0", bad("mul", 1..=2, 0, 1).to_string());
+    }
+
+    #[test]
+    fn check_pretty_errors() {
+        let mut program = Program::new("mov a\njmp nowhere\nmov 5, b\nend\n");
+        program.parse();
+        let report = program.pretty_errors();
+
+        assert!(report.contains("error: `mov` takes 2 operands, found 1\n --> line 1\n"));
+        assert!(report.contains("error: label `nowhere` is not defined\n"));
+        assert!(report.contains("error: `mov` needs a register or memory destination, found `5`\n --> line 3\n"));
+        assert!(report.ends_with("\n3 errors found\n"));
+
+        let mut program = Program::new("foo:\n    mov a, 1\nend\n");
+        program.parse();
+        assert_eq!("", program.pretty_errors());
+    }
+
+    #[test]
+    fn check_commands() {
+        let greeted = std::cell::RefCell::new(Vec::new());
+        let mut commands: HashMap<String, Handler> = HashMap::new();
+        commands.insert(String::from("greet"), Box::new(|values: &[i64]| {
+            greeted.borrow_mut().push(values.to_vec());
+            Some(values.iter().sum())
+        }));
+        commands.insert(String::from("Wave"), Box::new(|_: &[i64]| None));
+
+        let source = "mov b, 7\ngreet b, 1\nmov c, a\nwave\nGREET\nmsg c, ' ', a\nend\n";
+        let program = Program::new(source).with_commands(commands).with_strict_parsing();
+        let mut interpreter = Interpreter::from_program(program);
+        assert_eq!(Ok(Some(String::from("8 0"))), interpreter.run().map(Termination::into_output));
+        assert_eq!(vec![vec![7, 1], vec![]], *greeted.borrow());
+        assert_eq!("greet b, 1", interpreter.program.instructions[1].to_string());
+
+        // Without a handler the line stays a no-op
+        assert_eq!(Ok(Some(String::from("0 0"))), Interpreter::interpret(source).1);
+    }
+
+    #[test]
+    fn check_max_steps() {
+        let (interpreter, output) = Interpreter::interpret_with_limit("mov a, 1\nloop:\n    inc a\n    jmp loop\n", 100);
+        assert_eq!(Err(InterpretError::StepLimitExceeded(100)), output);
+        assert_eq!(100, interpreter.steps());
+
+        let (_, output) = Interpreter::interpret_with_limit("mov a, 1\nmsg a\nend\n", 3);
+        assert_eq!(Ok(Some(String::from("1"))), output);
+    }
+
+    #[test]
+    fn check_max_stack_depth() {
+        let program = "call recurse\nend\nrecurse:\n    inc a\n    call recurse\n    ret\n";
+        let mut interpreter = Interpreter::new(program).with_max_stack_depth(10);
+        assert_eq!(Err(InterpretError::StackOverflow { depth: 10, line: 5 }), interpreter.run());
+        assert_eq!(Some(&10), interpreter.register.get(&Symbol::from("a")));
+        assert_eq!(10, interpreter.metrics().peak_stack_depth);
+
+        let program = "mov n, 10\ncall count\nmsg 'depth ', n\nend\ncount:\n    dec n\n    cmp n, 0\n    je done\n    call count\ndone:\n    ret\n";
+        let output = Interpreter::new(program).with_max_stack_depth(10).run().map(Termination::into_output);
+        assert_eq!(Ok(Some(String::from("depth 0"))), output);
+    }
+
+    #[test]
+    fn check_arithmetic_modes() {
+        let program = "mov a, 9223372036854775806\nadd a, 5\nmov b, -9223372036854775807\ndec b\ndec b\nmsg a, ' ', b\nend\n";
+        let run = |source: &str, mode| Interpreter::new(source).with_arithmetic(mode).run().map(Termination::into_output);
+
+        assert_eq!(Ok(Some(String::from("-9223372036854775805 9223372036854775807"))), run(program, ArithmeticMode::Wrapping));
+        assert_eq!(Ok(Some(String::from("9223372036854775807 -9223372036854775808"))), run(program, ArithmeticMode::Saturating));
+        assert_eq!(Err(InterpretError::Overflow { register: String::from("a"), line: 2 }), run(program, ArithmeticMode::Checked));
+
+        let program = "mov a, 9223372036854775807\ninc a\nmul a, 2\nmsg a\nend\n";
+        assert_eq!(Ok(Some(String::from("0"))), run(program, ArithmeticMode::Wrapping));
+        assert_eq!(Ok(Some(String::from("9223372036854775807"))), run(program, ArithmeticMode::Saturating));
+        assert_eq!(Err(InterpretError::Overflow { register: String::from("a"), line: 2 }), run(program, ArithmeticMode::Checked));
+
+        // Narrow registers saturate at their own range and still report the carry
+        let mut interpreter = Interpreter::new("mov a, 250\nadd a, 10\nmov b, 3\nsub b, 5\nend\n").with_width(Width::Bits8).with_arithmetic(ArithmeticMode::Saturating);
+        interpreter.run().unwrap();
+        assert_eq!((Some(&255), Some(&0)), (interpreter.register.get(&Symbol::from("a")), interpreter.register.get(&Symbol::from("b"))));
+        assert_eq!((1, 1), (interpreter.cf, interpreter.zf));
+    }
+
+    #[test]
+    fn check_register_accessors() {
+        let (interpreter, _) = Interpreter::interpret("mov b, 2\nmov a, 5\nmul a, b\nmov [8], a\nend\n");
+        assert_eq!(Some(10), interpreter.register("a"));
+        assert_eq!(Some(2), interpreter.register("b"));
+        assert_eq!(None, interpreter.register("c"));
+        assert_eq!(None, interpreter.register("never_interned_register_name"));
+        assert_eq!(vec![("a", 10), ("b", 2)], interpreter.registers().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn check_state_accessors() {
+        let program = "call outer\nend\nouter:\n    call inner\n    ret\ninner:\n    cmp 1, 2\n    ret\n";
+        let mut interpreter = Interpreter::new(program);
+        while interpreter.rip() != 7 {
+            interpreter.step().unwrap();
+        }
+        assert_eq!(&[1, 4], interpreter.stack());
+        assert_eq!(Flags { zf: 0, cf: 1, sf: 1, of: 0, pf: 1 }, interpreter.flags());
+
+        assert_eq!(Ok(Termination::End(String::new())), interpreter.run());
+        assert!(interpreter.stack().is_empty());
+        assert_eq!(1, interpreter.rip());
+    }
+
+    #[test]
+    fn check_register_banks() {
+        let program = "mov r1, 1\nmov r2, 2\nmov s, 3\nctxsave saved\nmov r1, 10\nmov r2, 20\nmov s, 30\nmov t, 40\nctxcopy_prefix saved, r\nmsg r1, ' ', r2, ' ', s, ' ', t\nend\n";
+        let (interpreter, output) = Interpreter::interpret(program);
+        assert_eq!(Ok(Some(String::from("1 2 30 40"))), output);
+        assert_eq!(3, interpreter.banks[&Symbol::from("saved")].len());
+
+        // Loading a bank drops the registers created since it was saved
+        let loaded = program.replace("msg", "ctxload saved\nmsg");
+        let (interpreter, output) = Interpreter::interpret(&loaded);
+        assert_eq!(Ok(Some(String::from("1 2 3 0"))), output);
+        assert!(!interpreter.register.contains_key(&Symbol::from("t")));
+
+        // Banks survive saving and resuming
+        let mut paused = Interpreter::new(program);
+        for _ in 0..8 {
+            paused.step().unwrap();
+        }
+        let state = State::from_json(&paused.state().to_json()).unwrap();
+        assert_eq!(3, state.banks["saved"].len());
+        let mut resumed = Interpreter::continue_from(Program::new(program), state).unwrap();
+        assert_eq!(Ok(Termination::End(String::from("1 2 30 40"))), resumed.run());
+
+        // States written before banks existed still load
+        let mut json: serde_json::Value = serde_json::from_str(&Interpreter::new(program).state().to_json()).unwrap();
+        json.as_object_mut().unwrap().remove("banks");
+        assert!(State::from_json(&json.to_string()).unwrap().banks.is_empty());
+        // So do those from before events were counted
+        json.as_object_mut().unwrap().remove("events");
+        assert_eq!(0, State::from_json(&json.to_string()).unwrap().events);
+
+        let (_, output) = Interpreter::interpret("ctxcopy_prefix missing, r\nend\n");
+        assert_eq!(Err(InterpretError::UnknownBank { bank: String::from("missing"), line: 1 }), output);
+    }
+    #[test]
+    fn check_prepared_dispatch() {
+        // Each program runs once with the prepared handlers and once through the match in `execute` only
+        let programs = [
+            "mov a, 5\nmov b, a\nadd b, 'A'\nsub a, -3\nmul a, b\ninc c\ndec d\nmov a, a\nmsg a, ' ', b, ' ', c, ' ', d\nend\n",
+            "n equ 4\nmov a, n\nloop:\n    dec a\n    cmp a, 0\n    jg loop\n    jmp done\n    mov a, 99\ndone:\nmsg a\nend\n",
+            "mov [8], 3\nmov b, [8]\ninc [8]\nadd [b + 5], [8]\nmov c, $steps\ncmp c, rip\nmsg b, ' ', [8], ' ', c\nend\n",
+            "cmp a, f\njne skip\nmov a, 1\nskip:\nmov f, 1\ncmp f, 1\nmovne a, 7\njmpeq f\nend\nf:\nmsg a, ' ', f\nend\n",
+            "mov a, 9223372036854775807\ninc a\nmsg a\nend\n",
+            "mov a, 250\nadd a, 10\nmul a, 2\ncmp a, 8\nmsg a\nend\n",
+            "mov a, 1\njmp missing\nend\n",
+        ];
+        let configurations: [fn(Interpreter) -> Interpreter; 3] = [
+            |interpreter| interpreter,
+            |interpreter| interpreter.with_arithmetic(ArithmeticMode::Checked),
+            |interpreter| interpreter.with_width(Width::Bits8),
+        ];
+
+        for program in programs {
+            for configure in configurations {
+                let mut prepared = configure(Interpreter::new(program).with_seed(1));
+                let mut unprepared = configure(Interpreter::new(program).with_seed(1));
+                unprepared.prepared = Rc::new((0..unprepared.prepared.len()).map(|_| None).collect());
+                assert_eq!(unprepared.run(), prepared.run(), "{}", program);
+                assert_eq!(unprepared.state(), prepared.state(), "{}", program);
+                assert_eq!(unprepared.profile(), prepared.profile(), "{}", program);
+            }
+        }
+    }
+}
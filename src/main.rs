@@ -1,12 +1,18 @@
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use argh::FromArgs;
-use asmintr::Interpreter;
+use asmintr::{Cfg, Diagnostic, Divergence, Endianness, Instruction, InterpretError, Interpreter, LimitedSink, Severity, Termination, Trace, Width};
 
 /// Run assembly code
 #[derive(FromArgs)]
 struct Cli {
-    /// '.asm' file path
+    /// '.asm' file path, not needed with --stdin-loop
     #[argh(positional)]
-    file_name: String,
+    file_name: Option<String>,
 
     /// debug interpreter registers, stack, flags and output
     #[argh(switch, short = 'd')]
@@ -15,20 +21,675 @@ struct Cli {
     /// print parsed instructions
     #[argh(switch, short = 'i')]
     inst: bool,
+
+    /// print only the bare output without `Some(...)`, nothing when there is none, and no notes
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+
+    /// print the control-flow graph in Graphviz DOT format instead of running
+    #[argh(switch)]
+    cfg: bool,
+
+    /// print every instruction with a plain-English description instead of running
+    #[argh(switch)]
+    explain: bool,
+
+    /// print every source line with its tokens and parsed instruction instead of running
+    #[argh(switch)]
+    dump_parse_tree: bool,
+
+    /// print labels with their instruction indices
+    #[argh(switch)]
+    symbols: bool,
+
+    /// maximum number of distinct registers the program may create
+    #[argh(option)]
+    max_registers: Option<usize>,
+
+    /// maximum number of instructions the program may execute
+    #[argh(option)]
+    max_steps: Option<u64>,
+
+    /// maximum number of nested calls, 1000000 by default
+    #[argh(option)]
+    max_stack_depth: Option<usize>,
+
+    /// register width in bits (8, 16, 32 or 64), writes are truncated and arithmetic sets the flags
+    #[argh(option)]
+    registers_width: Option<u32>,
+
+    /// byte order of memory accesses, `little` (the default) or `big`
+    #[argh(option)]
+    endianness: Option<String>,
+
+    /// value for `read`, may be repeated
+    #[argh(option)]
+    input: Vec<i64>,
+
+    /// seed for `rand`, so every run gets the same numbers
+    #[argh(option)]
+    seed: Option<u64>,
+
+    /// start execution at the first instruction on or after this source line
+    #[argh(option)]
+    from_line: Option<usize>,
+
+    /// stop execution once it moves past this source line
+    #[argh(option)]
+    to_line: Option<usize>,
+
+    /// write the execution count of every source line as JSON to this file
+    #[argh(option)]
+    profile_json: Option<PathBuf>,
+
+    /// after the run, list the instructions that never executed and the share that did
+    #[argh(switch)]
+    coverage: bool,
+
+    /// write every executed instruction and the values it wrote as JSON to this file
+    #[argh(option)]
+    record_trace: Option<PathBuf>,
+
+    /// run along a trace written by --record-trace and report where the run differs from it
+    #[argh(option)]
+    replay_trace: Option<PathBuf>,
+
+    /// run the program twice and report the first step where the runs differ
+    #[argh(switch)]
+    check_determinism: bool,
+
+    /// run this program too with the same input and seed, and report the first value, flags or output that differ
+    #[argh(option)]
+    trace_diff: Option<PathBuf>,
+
+    /// pause after every instruction, Enter continues and `q` quits
+    #[argh(switch)]
+    step: bool,
+
+    /// print every message as soon as `msg` runs instead of only the final output
+    #[argh(switch)]
+    stream: bool,
+
+    /// print at most this many streamed messages and how many were left out, implies --stream
+    #[argh(option)]
+    limit_output_lines: Option<usize>,
+
+    /// re-run the program whenever the file changes
+    #[argh(switch, short = 'w')]
+    watch: bool,
+
+    /// run programs read from stdin one after another, each ended by a line holding only `---`
+    #[argh(switch)]
+    stdin_loop: bool,
 }
+
+// Polls a file's modification time
+struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Watcher {
+    fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), modified: None }
+    }
+
+    // True on the first call and whenever the modification time moved since the last call
+    fn changed(&mut self) -> io::Result<bool> {
+        let modified = std::fs::metadata(&self.path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(false);
+        }
+        self.modified = Some(modified);
+        Ok(true)
+    }
+}
+
+// Raised by Ctrl-C, the running program stops before its next instruction
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Ends a program read by --stdin-loop
+const PROGRAM_DELIMITER: &str = "---";
+
 fn main() {
     let cli: Cli = argh::from_env();
 
-    let content = std::fs::read_to_string(cli.file_name).unwrap();
-    let (interpreter, actual_output) = Interpreter::interpret(content.as_str());
+    if cli.stdin_loop {
+        if let Err(error) = run_loop(&cli, io::stdin().lock(), io::stdout()) {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let Some(file_name) = cli.file_name.as_deref() else {
+        eprintln!("Error: a file name is needed unless --stdin-loop is given");
+        std::process::exit(1);
+    };
+
+    if !cli.watch {
+        if let Err(error) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)) {
+            eprintln!("Error: {}", error);
+        }
+        let content = std::fs::read_to_string(file_name).unwrap();
+        if !execute(&cli, &content) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut watcher = Watcher::new(file_name);
+    loop {
+        match watcher.changed() {
+            Ok(true) => {
+                // Clear the screen before printing fresh output
+                print!("\x1B[2J\x1B[1;1H");
+                match std::fs::read_to_string(file_name) {
+                    Ok(content) => {
+                        execute(&cli, &content);
+                    }
+                    Err(error) => eprintln!("Error: {}", error),
+                }
+            }
+            Ok(false) => {}
+            Err(error) => eprintln!("Error: {}", error),
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// Run a single program, returning whether it finished without errors
+fn execute(cli: &Cli, content: &str) -> bool {
+    let mut interpreter = Interpreter::new(content).with_input(&cli.input).with_stop_flag(&INTERRUPTED).with_real_time();
+    eprint!("{}", interpreter.program.pretty_errors());
+    if interpreter.program.diagnostics().iter().any(|diagnostic| diagnostic.severity == Severity::Error) {
+        return false;
+    }
+    if cli.cfg {
+        print!("{}", Cfg::build(&interpreter.program).to_dot());
+        return true;
+    }
+    if cli.dump_parse_tree {
+        print!("{}", interpreter.program.dump_parse_tree());
+        return true;
+    }
+    if cli.explain {
+        for instruction in interpreter.program.instructions.iter() {
+            match instruction {
+                Instruction::Nop => {}
+                Instruction::Function(_) => println!("{}", instruction),
+                _ => println!("    {:<24}; {}", instruction.to_string(), instruction.describe()),
+            }
+        }
+        return true;
+    }
+    if cli.symbols {
+        println!("Symbols:");
+        for (name, index) in interpreter.program.symbols() {
+            println!("{:<10}: {}", name, index);
+        }
+    }
+    if let Some(bits) = cli.registers_width {
+        if Width::from_bits(bits).is_none() {
+            eprintln!("Error: --registers-width must be 8, 16, 32 or 64, not {}", bits);
+            return false;
+        }
+    }
+    if let Some(endianness) = cli.endianness.as_deref() {
+        if endianness != "little" && endianness != "big" {
+            eprintln!("Error: --endianness must be little or big, not {}", endianness);
+            return false;
+        }
+    }
+    interpreter = configure(cli, interpreter);
+    if cli.check_determinism {
+        let mut second = configure(cli, Interpreter::new(content).with_input(&cli.input).with_stop_flag(&INTERRUPTED));
+        return match check_determinism(&mut interpreter, &mut second) {
+            Ok(()) => {
+                println!("Both runs went the same way");
+                true
+            }
+            Err(divergence) => {
+                eprintln!("Error: the runs differ, {}", divergence);
+                false
+            }
+        };
+    }
+    if let Some(path) = &cli.trace_diff {
+        let other = match std::fs::read_to_string(path) {
+            Ok(other) => other,
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                return false;
+            }
+        };
+        return match diff_programs(cli, content, &other) {
+            Ok(()) => {
+                println!("Both programs wrote and output the same values");
+                true
+            }
+            Err(divergence) => {
+                eprintln!("Error: the programs differ, {}", divergence);
+                false
+            }
+        };
+    }
+    if let Some(path) = &cli.replay_trace {
+        let trace = match std::fs::read_to_string(path).map(|json| Trace::from_json(&json)) {
+            Ok(Some(trace)) => trace,
+            Ok(None) => {
+                eprintln!("Error: {} is not a trace", path.display());
+                return false;
+            }
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                return false;
+            }
+        };
+        return match trace.replay(&mut interpreter) {
+            Ok(()) => {
+                println!("The run matches the trace");
+                true
+            }
+            Err(divergence) => {
+                eprintln!("Error: {}", divergence);
+                false
+            }
+        };
+    }
+
+    let streaming = cli.stream || cli.limit_output_lines.is_some();
+    if streaming {
+        interpreter = interpreter.with_sink(LimitedSink::new(io::stdout(), cli.limit_output_lines.unwrap_or(usize::MAX)));
+    }
+
+    let result = if cli.step {
+        match step_through(&mut interpreter, io::stdin().lock(), io::stdout()) {
+            Ok(Some(result)) => result,
+            Ok(None) => return true,
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                return false;
+            }
+        }
+    } else if let Some(path) = &cli.record_trace {
+        let (trace, result) = Trace::record(&mut interpreter);
+        if let Err(error) = std::fs::write(path, trace.to_json()) {
+            eprintln!("Error: {}", error);
+        }
+        result
+    } else {
+        interpreter.run()
+    };
+
+    if let Some(path) = &cli.profile_json {
+        if let Err(error) = std::fs::write(path, profile_json(&interpreter)) {
+            eprintln!("Error: {}", error);
+        }
+    }
+
+    if cli.coverage {
+        print!("{}", coverage_report(&interpreter));
+    }
 
     if cli.inst {
         println!("Instructions: {:?}", interpreter.program.instructions);
     }
 
-    if cli.debug {
-        println!("{}\nActual Output is : {:?}", interpreter, actual_output);
+    match result {
+        Ok(termination) => {
+            match &termination {
+                Termination::Aborted(message) => {
+                    if cli.debug {
+                        println!("{}", interpreter);
+                    }
+                    eprintln!("Error: aborted: {}", message);
+                    return false;
+                }
+                Termination::FellThrough if !cli.quiet => {
+                    eprintln!("note: the program finished without reaching `end`, so it has no output")
+                }
+                Termination::Interrupted(_) if !cli.quiet => {
+                    eprintln!("note: interrupted, the output is partial");
+                    if !cli.debug {
+                        eprintln!("{}", interpreter);
+                    }
+                }
+                _ => {}
+            }
+            let actual_output = termination.into_output();
+            if cli.debug {
+                println!("{}\n{}\nActual Output is : {:?}", interpreter, interpreter.metrics(), actual_output);
+            } else if let Some(line) = output_line(&actual_output, cli.quiet).filter(|_| !streaming) {
+                println!("{}", line);
+            }
+            true
+        }
+        Err(error) => {
+            if cli.debug {
+                println!("{}", interpreter);
+            }
+            eprint!("{}", Diagnostic::from(&error).render(content));
+            false
+        }
+    }
+}
+
+// Apply the options that change how the program runs
+fn configure<'a>(cli: &Cli, mut interpreter: Interpreter<'a>) -> Interpreter<'a> {
+    if let Some(seed) = cli.seed {
+        interpreter = interpreter.with_seed(seed);
+    }
+    if let Some(limit) = cli.max_registers {
+        interpreter = interpreter.with_max_registers(limit);
+    }
+    if let Some(limit) = cli.max_steps {
+        interpreter = interpreter.with_max_steps(limit);
+    }
+    if let Some(depth) = cli.max_stack_depth {
+        interpreter = interpreter.with_max_stack_depth(depth);
+    }
+    if let Some(width) = cli.registers_width.and_then(Width::from_bits) {
+        interpreter = interpreter.with_width(width);
+    }
+    if cli.endianness.as_deref() == Some("big") {
+        interpreter = interpreter.with_endianness(Endianness::Big);
+    }
+    if cli.from_line.is_some() || cli.to_line.is_some() {
+        interpreter = interpreter.with_line_range(cli.from_line.unwrap_or(1), cli.to_line.unwrap_or(usize::MAX));
+    }
+    interpreter
+}
+
+// Run every program read from `input` with a fresh interpreter, writing one line for each: its output,
+// or the error it stopped with. A program ends at a line holding only `---` or at the end of the input
+fn run_loop(cli: &Cli, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut source = String::new();
+    let mut lines = input.lines();
+    loop {
+        let line = lines.next().transpose()?;
+        match line.as_deref() {
+            Some(PROGRAM_DELIMITER) | None => {
+                if !source.trim().is_empty() {
+                    writeln!(output, "{}", run_source(cli, &source))?;
+                    output.flush()?;
+                }
+                source.clear();
+                if line.is_none() {
+                    return Ok(());
+                }
+            }
+            Some(line) => {
+                source.push_str(line);
+                source.push('\n');
+            }
+        }
+    }
+}
+
+// The line --stdin-loop prints for a program
+fn run_source(cli: &Cli, source: &str) -> String {
+    let mut interpreter = configure(cli, Interpreter::new(source).with_input(&cli.input));
+    match interpreter.run() {
+        Ok(Termination::Aborted(message)) => format!("Error: aborted: {}", message),
+        Ok(termination) => output_line(&termination.into_output(), cli.quiet).unwrap_or_default(),
+        Err(error) => format!("Error: {}", error),
+    }
+}
+
+// Run the first interpreter and replay the second along it. Failing the same way twice is deterministic too
+fn check_determinism(first: &mut Interpreter, second: &mut Interpreter) -> Result<(), Divergence> {
+    let (trace, result) = Trace::record(first);
+    match (result, trace.replay(second)) {
+        (Err(error), Err(Divergence::Error(again))) if error == again => Ok(()),
+        (_, replayed) => replayed,
+    }
+}
+
+// Run two programs configured by the options for --trace-diff. Without --seed both still draw the same
+// random numbers, so only the programs can make them differ
+fn diff_programs(cli: &Cli, first: &str, second: &str) -> Result<(), Divergence> {
+    let seed = cli.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64));
+    let run = |source| configure(cli, Interpreter::new(source).with_input(&cli.input).with_stop_flag(&INTERRUPTED)).with_seed(seed);
+    trace_diff(&mut run(first), &mut run(second))
+}
+
+// Run two programs and compare what they wrote and output. Failing the same way counts as the same too
+fn trace_diff(first: &mut Interpreter, second: &mut Interpreter) -> Result<(), Divergence> {
+    let (expected, first_result) = Trace::record(first);
+    let (actual, second_result) = Trace::record(second);
+    expected.diff(&actual)?;
+    match (first_result, second_result) {
+        (Err(error), Err(again)) if error == again => Ok(()),
+        (Err(error), _) | (_, Err(error)) => Err(Divergence::Error(error)),
+        _ => Ok(()),
+    }
+}
+
+// Run one instruction at a time, printing it with the values it wrote and waiting for a line of input.
+// Returns None when the user quit with `q`
+fn step_through(
+    interpreter: &mut Interpreter,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<Option<Result<Termination, InterpretError>>> {
+    let program = Rc::clone(&interpreter.program);
+
+    loop {
+        let step = match interpreter.step() {
+            Ok(step) => step,
+            Err(error) => return Ok(Some(Err(error))),
+        };
+        if let Some(termination) = step.termination {
+            return Ok(Some(Ok(termination)));
+        }
+
+        let instruction = &program.instructions[step.index];
+        if matches!(instruction, Instruction::Function(_) | Instruction::Nop) {
+            continue;
+        }
+        writeln!(output, "{:>4}: {}", step.index, instruction)?;
+        for (name, value) in step.writes.iter() {
+            writeln!(output, "      {} = {}", name, value)?;
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        if line.trim() == "q" {
+            return Ok(None);
+        }
+    }
+}
+
+// What the CLI prints for the output of a finished run, if anything
+fn output_line(output: &Option<String>, quiet: bool) -> Option<String> {
+    if quiet {
+        output.clone()
     } else {
-        println!("{:?}", actual_output);
+        Some(format!("{:?}", output))
     }
-}
\ No newline at end of file
+}
+
+fn profile_json(interpreter: &Interpreter) -> String {
+    serde_json::to_string_pretty(&interpreter.line_profile()).unwrap()
+}
+
+// Share of the program's instructions that ran at least once, followed by those that never did.
+// Built-in subroutines are left out
+fn coverage_report(interpreter: &Interpreter) -> String {
+    let program = &interpreter.program;
+    let instructions: Vec<(usize, usize)> = program.instructions.iter().enumerate()
+        .filter(|(_, instruction)| !matches!(instruction, Instruction::Function(_) | Instruction::Nop))
+        .filter_map(|(index, _)| Some((index, program.line_of(index)?)))
+        .collect();
+    let uncovered: Vec<&(usize, usize)> = instructions.iter().filter(|&&(index, _)| interpreter.profile()[index] == 0).collect();
+
+    let covered = instructions.len() - uncovered.len();
+    let percentage = match instructions.len() {
+        0 => 100.0,
+        total => covered as f64 * 100.0 / total as f64,
+    };
+    let mut report = format!("Coverage: {} of {} instructions ({:.1}%)\n", covered, instructions.len(), percentage);
+    if !uncovered.is_empty() {
+        report.push_str("Never executed:\n");
+        for &&(index, line) in uncovered.iter() {
+            report.push_str(&format!("{:>6}: {}\n", line, program.instructions[index]));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_profile_json() {
+        let program = "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n";
+        let mut interpreter = Interpreter::new(program);
+        interpreter.run().unwrap();
+
+        let profile: serde_json::Value = serde_json::from_str(&profile_json(&interpreter)).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "2": 1, "3": 1, "4": 1, "5": 1, "6": 1, "7": 1,
+                "10": 4, "11": 4, "12": 4, "13": 4, "14": 1,
+                "17": 1, "18": 1
+            }),
+            profile
+        );
+    }
+
+    #[test]
+    fn check_watcher() {
+        let path = std::env::temp_dir().join(format!("asmintr-watch-{}.asm", std::process::id()));
+        std::fs::write(&path, "end\n").unwrap();
+
+        let mut watcher = Watcher::new(&path);
+        assert!(watcher.changed().unwrap());
+        assert!(!watcher.changed().unwrap());
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(watcher.changed().unwrap());
+        assert!(!watcher.changed().unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn check_step_through() {
+        let mut interpreter = Interpreter::new("mov a, 5\ninc a\n\nlabel:\nmov [0], a\nmsg 'a = ', a\nend\n");
+        let mut output = Vec::new();
+        let result = step_through(&mut interpreter, "\n\n\n\n".as_bytes(), &mut output).unwrap();
+
+        assert_eq!(Some(Ok(Termination::End(String::from("a = 6")))), result);
+        assert_eq!(
+            concat!(
+                "   0: mov a, 5\n      a = 5\n> ",
+                "   1: inc a\n      a = 6\n> ",
+                "   4: mov [0], a\n      [0] = 6\n> ",
+                "   5: msg 'a = ', a\n> "
+            ),
+            String::from_utf8(output).unwrap()
+        );
+
+        let mut interpreter = Interpreter::new("mov a, 5\ninc a\nend\n");
+        let mut output = Vec::new();
+        assert_eq!(None, step_through(&mut interpreter, "\nq\n".as_bytes(), &mut output).unwrap());
+        assert_eq!(2, interpreter.steps());
+    }
+
+    #[test]
+    fn check_determinism_mode() {
+        let program = "mov a, 3\nloop:\ndec a\njcxz a, done\njmp loop\ndone:\nmsg 'done'\nend\n";
+        assert_eq!(Ok(()), check_determinism(&mut Interpreter::new(program), &mut Interpreter::new(program)));
+
+        let program = "mov a, 1\nret\n";
+        assert_eq!(Ok(()), check_determinism(&mut Interpreter::new(program), &mut Interpreter::new(program)));
+
+        let program = "rand a\nmsg a\nend\n";
+        let divergence = check_determinism(&mut Interpreter::new(program), &mut Interpreter::new(program));
+        assert!(matches!(divergence, Err(Divergence::Step { step: 0, .. })));
+
+        let result = check_determinism(&mut Interpreter::new(program).with_seed(1), &mut Interpreter::new(program).with_seed(1));
+        assert_eq!(Ok(()), result);
+    }
+
+    #[test]
+    fn check_quiet_output() {
+        let (_, output) = Interpreter::interpret("msg 'hello'\nend\n");
+        let output = output.unwrap();
+        assert_eq!(Some(String::from("Some(\"hello\")")), output_line(&output, false));
+        assert_eq!(Some(String::from("hello")), output_line(&output, true));
+
+        let (_, output) = Interpreter::interpret("msg 'hello'\n");
+        let output = output.unwrap();
+        assert_eq!(Some(String::from("None")), output_line(&output, false));
+        assert_eq!(None, output_line(&output, true));
+    }
+
+    #[test]
+    fn check_coverage_report() {
+        let program = "mov a, 5\ncmp a, 5\njne different\nmsg 'same'\nend\ndifferent:\n    inc a\n    msg 'different'\n    end\n";
+        let mut interpreter = Interpreter::new(program);
+        interpreter.run().unwrap();
+
+        assert_eq!(
+            concat!(
+                "Coverage: 5 of 8 instructions (62.5%)\n",
+                "Never executed:\n",
+                "     7: inc a\n",
+                "     8: msg 'different'\n",
+                "     9: end\n"
+            ),
+            coverage_report(&interpreter)
+        );
+    }
+
+    #[test]
+    fn check_stdin_loop() {
+        let cli = Cli::from_args(&["asmintr"], &["--stdin-loop"]).unwrap();
+        let input = "mov a, 5\nmsg 'a = ', a\nend\n---\nmsg 'a = ', a\nend\n---\n\n---\nret\n";
+        let mut output = Vec::new();
+        run_loop(&cli, input.as_bytes(), &mut output).unwrap();
+
+        assert_eq!(
+            "Some(\"a = 5\")\nSome(\"a = 0\")\nError: `ret` without a matching `call`\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn check_trace_diff() {
+        let original = "read n\nloop:\n    add sum, n\n    dec n\n    cmp n, 0\n    jne loop\nmsg 'sum = ', sum\nend\n";
+        let rewritten = "read n\nloop:\n    add sum, n\n    dec n\n    cmp n, 0\n    je done\n    jmp loop\ndone:\n    msg 'sum = ', sum\n    end\n";
+        let run = |source| Interpreter::new(source).with_input(&[4]);
+        assert_eq!(Ok(()), trace_diff(&mut run(original), &mut run(rewritten)));
+
+        let broken = rewritten.replacen("dec n", "sub n, 2", 1);
+        let divergence = trace_diff(&mut run(original), &mut run(&broken));
+        assert!(matches!(divergence, Err(Divergence::Write { number: 2, .. })));
+        assert_eq!(
+            "write 2 differs: in the first run instruction 3 wrote [(\"n\", 3)], in the second instruction 3 wrote [(\"n\", 2)]",
+            divergence.unwrap_err().to_string()
+        );
+
+        let renamed = original.replacen("msg 'sum = ', sum", "msg 'total = ', sum", 1);
+        let divergence = trace_diff(&mut run(original), &mut run(&renamed));
+        assert!(matches!(divergence, Err(Divergence::Output { .. })));
+
+        // Flags count as written too, even when the jumps still go the same way
+        let swapped = rewritten.replacen("cmp n, 0", "cmp 0, n", 1);
+        let divergence = trace_diff(&mut run(original), &mut run(&swapped));
+        assert_eq!(
+            "write 3 differs: in the first run instruction 4 wrote [] setting the flags to 0b10000, in the second instruction 4 wrote [] setting the flags to 0b00110",
+            divergence.unwrap_err().to_string()
+        );
+
+        // Without --seed both programs still get the same random numbers
+        let cli = Cli::from_args(&["asmintr"], &["--trace-diff", "other.asm", "program.asm"]).unwrap();
+        assert_eq!(Ok(()), diff_programs(&cli, "rand x\nmsg x\nend\n", "rand x\nnop\nmsg x\nend\n"));
+    }
+}
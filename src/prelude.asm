@@ -0,0 +1,41 @@
+; Built-in subroutines, added to programs that call them.
+; Arguments are passed in a and b and the result is returned in a.
+; b, the flags and the scratch registers __r and __t are clobbered.
+
+; a = gcd(a, b), for non-negative a and b
+__gcd:
+    cmp   b, 0
+    je    __gcd_done
+    mov   __t, a
+    div   __t, b
+    mul   __t, b
+    mov   __r, a
+    sub   __r, __t        ; a % b
+    mov   a, b
+    mov   b, __r
+    jmp   __gcd
+__gcd_done:
+    ret
+
+; a = a^b, for non-negative b
+__pow:
+    mov   __r, 1
+__pow_loop:
+    cmp   b, 0
+    jle   __pow_done
+    mul   __r, a
+    dec   b
+    jmp   __pow_loop
+__pow_done:
+    mov   a, __r
+    ret
+
+; a = |a|
+__abs:
+    cmp   a, 0
+    jge   __abs_done
+    mov   __r, 0
+    sub   __r, a
+    mov   a, __r
+__abs_done:
+    ret
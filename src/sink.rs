@@ -0,0 +1,163 @@
+use std::io::{self, Stdout, Write};
+
+pub trait OutputSink {
+    fn write_message(&mut self, message: &str);
+
+    // Called by the interpreter with the number of the event that emitted the message. Messages,
+    // `msgerr` messages and steps share one counter, so sinks merging them can order them by it
+    fn write_sequenced(&mut self, _sequence: u64, message: &str) {
+        self.write_message(message);
+    }
+
+    // Message of `msgerr`, printed on stderr unless the sink keeps it somewhere else
+    fn write_error(&mut self, _sequence: u64, message: &str) {
+        let _ = writeln!(io::stderr(), "{}", message);
+    }
+
+    // Called by `flush`, sinks that buffer emit their pending messages here
+    fn flush(&mut self) {}
+
+    // Called once the run is over, sinks that held messages back summarize them here
+    fn finish(&mut self) {}
+}
+
+impl<T: OutputSink + ?Sized> OutputSink for &mut T {
+    fn write_message(&mut self, message: &str) {
+        (**self).write_message(message);
+    }
+
+    fn write_sequenced(&mut self, sequence: u64, message: &str) {
+        (**self).write_sequenced(sequence, message);
+    }
+
+    fn write_error(&mut self, sequence: u64, message: &str) {
+        (**self).write_error(sequence, message);
+    }
+
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+
+    fn finish(&mut self) {
+        (**self).finish();
+    }
+}
+
+// Stands in when the interpreter has no sink: messages are dropped, `msgerr` keeps the default
+pub(crate) struct NoSink;
+
+impl OutputSink for NoSink {
+    fn write_message(&mut self, _message: &str) {}
+}
+
+impl OutputSink for Vec<String> {
+    fn write_message(&mut self, message: &str) {
+        self.push(message.to_string());
+    }
+}
+
+// Prints every message on its own line as soon as it arrives
+impl OutputSink for Stdout {
+    fn write_message(&mut self, message: &str) {
+        let _ = writeln!(self, "{}", message);
+    }
+
+    fn flush(&mut self) {
+        let _ = Write::flush(self);
+    }
+}
+
+// Holds messages back until the program runs `flush`
+pub struct BufferedSink<S: OutputSink> {
+    inner: S,
+    // Messages with their sequence numbers, `write_message` has none
+    pending: Vec<(Option<u64>, String)>,
+}
+
+impl<S: OutputSink> BufferedSink<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, pending: Vec::new() }
+    }
+}
+
+impl<S: OutputSink> OutputSink for BufferedSink<S> {
+    fn write_message(&mut self, message: &str) {
+        self.pending.push((None, message.to_string()));
+    }
+
+    fn write_sequenced(&mut self, sequence: u64, message: &str) {
+        self.pending.push((Some(sequence), message.to_string()));
+    }
+
+    // Errors aren't held back
+    fn write_error(&mut self, sequence: u64, message: &str) {
+        self.inner.write_error(sequence, message);
+    }
+
+    fn flush(&mut self) {
+        for (sequence, message) in self.pending.drain(..) {
+            match sequence {
+                Some(sequence) => self.inner.write_sequenced(sequence, &message),
+                None => self.inner.write_message(&message),
+            }
+        }
+        self.inner.flush();
+    }
+
+    // Messages after the last `flush` still come out once the run is over
+    fn finish(&mut self) {
+        self.flush();
+        self.inner.finish();
+    }
+}
+
+// Passes on the first `limit` messages and counts the rest, which `finish` reports as `... (N more)`
+pub struct LimitedSink<S: OutputSink> {
+    inner: S,
+    limit: usize,
+    written: usize,
+    left_out: usize,
+}
+
+impl<S: OutputSink> LimitedSink<S> {
+    pub fn new(inner: S, limit: usize) -> Self {
+        Self { inner, limit, written: 0, left_out: 0 }
+    }
+}
+
+impl<S: OutputSink> OutputSink for LimitedSink<S> {
+    fn write_message(&mut self, message: &str) {
+        if self.written < self.limit {
+            self.written += 1;
+            self.inner.write_message(message);
+        } else {
+            self.left_out += 1;
+        }
+    }
+
+    fn write_sequenced(&mut self, sequence: u64, message: &str) {
+        if self.written < self.limit {
+            self.written += 1;
+            self.inner.write_sequenced(sequence, message);
+        } else {
+            self.left_out += 1;
+        }
+    }
+
+    // Only messages count against the limit
+    fn write_error(&mut self, sequence: u64, message: &str) {
+        self.inner.write_error(sequence, message);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn finish(&mut self) {
+        if self.left_out > 0 {
+            self.inner.write_message(&format!("... ({} more)", self.left_out));
+            self.left_out = 0;
+        }
+        self.inner.finish();
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+// Everything a paused run needs to continue, see `Interpreter::state` and `Interpreter::continue_from`.
+// Options like the register width are not part of it and have to be given again
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    // Hash of the program source, so the state is only resumed with the program it came from
+    pub fingerprint: u64,
+    pub rip: usize,
+    pub stack: Vec<usize>,
+    pub results: Vec<Option<String>>,
+    pub values: Vec<i64>,
+    pub registers: BTreeMap<String, i64>,
+    // Register sets saved with `ctxsave`, by bank name
+    pub banks: BTreeMap<String, BTreeMap<String, i64>>,
+    pub zf: u8,
+    pub cf: u8,
+    pub sf: u8,
+    pub of: u8,
+    pub pf: u8,
+    pub memory: BTreeMap<i64, u8>,
+    pub input: Vec<i64>,
+    pub out: String,
+    pub steps: u64,
+    pub events: u64,
+    pub cycles: u64,
+    pub clock: u64,
+    pub random: u64,
+    pub peak_stack: usize,
+    pub profile: Vec<u64>,
+}
+
+// Format of `to_json`, raised when a change would make older readers misread a state. States
+// without one predate versioning and are read as version 1
+const VERSION: u64 = 1;
+
+// FNV-1a, stable between builds unlike the std hasher
+pub(crate) fn fingerprint(source: &str) -> u64 {
+    source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+impl State {
+    pub fn to_json(&self) -> String {
+        let memory: Vec<(i64, u8)> = self.memory.iter().map(|(&address, &byte)| (address, byte)).collect();
+        let value = json!({
+            "version": VERSION,
+            "fingerprint": self.fingerprint,
+            "rip": self.rip,
+            "stack": self.stack,
+            "results": self.results,
+            "values": self.values,
+            "registers": self.registers,
+            "banks": self.banks,
+            "zf": self.zf,
+            "cf": self.cf,
+            "sf": self.sf,
+            "of": self.of,
+            "pf": self.pf,
+            "memory": memory,
+            "input": self.input,
+            "out": self.out,
+            "steps": self.steps,
+            "events": self.events,
+            "cycles": self.cycles,
+            "clock": self.clock,
+            "random": self.random,
+            "peak_stack": self.peak_stack,
+            "profile": self.profile,
+        });
+        serde_json::to_string_pretty(&value).unwrap()
+    }
+
+    // None when `json` isn't a state written by `to_json`, or by a newer version of it
+    pub fn from_json(json: &str) -> Option<State> {
+        let value: Value = serde_json::from_str(json).ok()?;
+        let number = |key: &str| value.get(key)?.as_u64();
+        let index = |number: u64| usize::try_from(number).ok();
+        let flag = |key: &str| u8::try_from(number(key)?).ok().filter(|&flag| flag <= 1);
+        let list = |key: &str| value.get(key)?.as_array();
+        if number("version").unwrap_or(1) > VERSION {
+            return None;
+        }
+        Some(State {
+            fingerprint: number("fingerprint")?,
+            rip: index(number("rip")?)?,
+            stack: list("stack")?.iter().map(|entry| index(entry.as_u64()?)).collect::<Option<_>>()?,
+            results: list("results")?.iter()
+                .map(|entry| match entry {
+                    Value::Null => Some(None),
+                    entry => Some(Some(entry.as_str()?.to_string())),
+                })
+                .collect::<Option<_>>()?,
+            values: list("values")?.iter().map(Value::as_i64).collect::<Option<_>>()?,
+            registers: value.get("registers")?.as_object()?.iter()
+                .map(|(name, value)| Some((name.clone(), value.as_i64()?)))
+                .collect::<Option<_>>()?,
+            // States saved before register banks existed have none
+            banks: match value.get("banks") {
+                Some(banks) => banks.as_object()?.iter()
+                    .map(|(bank, registers)| {
+                        let registers = registers.as_object()?.iter()
+                            .map(|(name, value)| Some((name.clone(), value.as_i64()?)))
+                            .collect::<Option<_>>()?;
+                        Some((bank.clone(), registers))
+                    })
+                    .collect::<Option<_>>()?,
+                None => Default::default(),
+            },
+            zf: flag("zf")?,
+            cf: flag("cf")?,
+            sf: flag("sf")?,
+            of: flag("of")?,
+            pf: flag("pf")?,
+            memory: list("memory")?.iter()
+                .map(|entry| Some((entry.get(0)?.as_i64()?, u8::try_from(entry.get(1)?.as_u64()?).ok()?)))
+                .collect::<Option<_>>()?,
+            input: list("input")?.iter().map(Value::as_i64).collect::<Option<_>>()?,
+            out: value.get("out")?.as_str()?.to_string(),
+            steps: number("steps")?,
+            // Missing in states saved before events were counted
+            events: number("events").unwrap_or(0),
+            cycles: number("cycles")?,
+            clock: number("clock")?,
+            // The generator never leaves 0
+            random: number("random").filter(|&random| random != 0)?,
+            peak_stack: index(number("peak_stack")?)?,
+            profile: list("profile")?.iter().map(Value::as_u64).collect::<Option<_>>()?,
+        })
+    }
+}
@@ -0,0 +1,206 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+
+// Register, label or operand name. The name is hashed once when the symbol is made, so hashing a
+// symbol never touches the text, and symbols of one table share their text, so comparing equal
+// ones doesn't either
+#[derive(Clone)]
+pub struct Symbol {
+    hash: u64,
+    name: Rc<str>,
+}
+
+// Names of one program, dropped along with it. Only parsing interns, running only looks names up,
+// so running a program again, like `--watch` and `--stdin-loop` do, adds nothing to the table
+#[derive(Default)]
+pub struct SymbolTable {
+    ids: HashMap<Rc<str>, u32>,
+    // Indexed by id
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        let id = self.id(name);
+        self.symbols[id as usize].clone()
+    }
+
+    // Position of `name` in the table, interning it first if needed
+    pub fn id(&mut self, name: &str) -> u32 {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+
+        let symbol = Symbol::new(name);
+        let id = self.symbols.len() as u32;
+        self.ids.insert(symbol.name.clone(), id);
+        self.symbols.push(symbol);
+        id
+    }
+
+    // The symbol for `name` if it was interned, without interning it
+    pub fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).map(|&id| self.symbols[id as usize].clone())
+    }
+
+    // The symbol interned with `id`
+    pub fn get(&self, id: u32) -> Option<Symbol> {
+        self.symbols.get(id as usize).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+// FNV-1a, the same name gets the same hash in every table
+fn hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+impl Symbol {
+    // A symbol outside of any table, equal to the symbols other tables have for `name`
+    pub fn new(name: &str) -> Self {
+        Symbol { hash: hash(name), name: name.into() }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::new(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::new(&name)
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && (Rc::ptr_eq(&self.name, &other.name) || self.name == other.name)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.name == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.name == *other
+    }
+}
+
+impl PartialEq<Symbol> for &str {
+    fn eq(&self, other: &Symbol) -> bool {
+        *self == &*other.name
+    }
+}
+
+// Map keyed by symbols, using the hash they carry as is
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<IdHasher>>;
+
+#[derive(Default)]
+pub struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0 << 8 | byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+// Ordered by name, not by hash
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&*self.name, f)
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.name, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_interning() {
+        let mut table = SymbolTable::default();
+        let a = table.intern("a");
+        assert_eq!(a, table.intern("a"));
+        assert!(Rc::ptr_eq(&a.name, &table.intern("a").name));
+        assert_eq!(1, table.len());
+        assert_eq!(a, Symbol::from(String::from("a")));
+        assert_ne!(a, table.intern("b"));
+        assert_eq!("a", a.as_str());
+        assert_eq!(Some(a), table.lookup("a"));
+        assert_eq!(None, table.lookup("never_interned"));
+        let id = table.id("b");
+        assert_eq!(Some(table.intern("b")), table.get(id));
+        assert_eq!(None, table.get(u32::MAX));
+
+        // A name hashes the same in every table, so symbols of different programs find each other
+        let mut other = SymbolTable::default();
+        let b = other.intern("b");
+        let mut map = SymbolMap::default();
+        map.insert(table.intern("b"), 1);
+        assert_eq!(Some(&1), map.get(&b));
+    }
+}
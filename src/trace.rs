@@ -0,0 +1,215 @@
+use std::fmt::{Display, Formatter};
+
+use serde_json::{json, Value};
+
+use crate::{InterpretError, Interpreter, Termination};
+
+// One executed instruction with the registers and memory cells it wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub index: usize,
+    pub writes: Vec<(String, i64)>,
+    // Flags packed like `lahf` does, when the step changed them
+    pub flags: Option<u8>,
+    // Sequence number shared with the messages of the run, it doesn't count when comparing runs
+    pub event: u64,
+}
+
+impl TraceStep {
+    fn same_as(&self, other: &TraceStep) -> bool {
+        self.index == other.index && self.writes == other.writes && self.flags == other.flags
+    }
+
+    // Whether the step changed anything
+    fn changes(&self) -> bool {
+        !self.writes.is_empty() || self.flags.is_some()
+    }
+
+    fn describe(&self) -> String {
+        match self.flags {
+            Some(flags) => format!("instruction {} wrote {:?} setting the flags to {:#07b}", self.index, self.writes, flags),
+            None => format!("instruction {} wrote {:?}", self.index, self.writes),
+        }
+    }
+}
+
+// Everything a run did, in a form another run can be replayed against
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+    pub output: Option<String>,
+}
+
+// First place where a replay went differently than the recorded run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    Step { step: usize, expected: TraceStep, actual: TraceStep },
+    // The replay stopped after `actual` steps while the trace has `expected`
+    Length { expected: usize, actual: usize },
+    Output { expected: Option<String>, actual: Option<String> },
+    // Runs of two programs wrote something different the `number`th time, None when one stopped writing
+    Write { number: usize, expected: Option<TraceStep>, actual: Option<TraceStep> },
+    Error(InterpretError),
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Divergence::Step { step, expected, actual } => {
+                write!(f, "step {} ran {}, the trace has {}", step, actual.describe(), expected.describe())
+            }
+            Divergence::Length { expected, actual } => {
+                write!(f, "the run took {} steps, the trace has {}", actual, expected)
+            }
+            Divergence::Output { expected, actual } => {
+                write!(f, "the run output {:?}, the trace has {:?}", actual, expected)
+            }
+            Divergence::Write { number, expected, actual } => {
+                let describe = |step: &Option<TraceStep>| match step {
+                    Some(step) => step.describe(),
+                    None => String::from("nothing was written"),
+                };
+                write!(f, "write {} differs: in the first run {}, in the second {}", number, describe(expected), describe(actual))
+            }
+            Divergence::Error(error) => write!(f, "the run failed: {}", error),
+        }
+    }
+}
+
+impl Trace {
+    // Run the interpreter to the end one step at a time. The trace covers the steps before an error too
+    pub fn record(interpreter: &mut Interpreter) -> (Trace, Result<Termination, InterpretError>) {
+        let mut trace = Trace::default();
+        loop {
+            match interpreter.step() {
+                Ok(step) => match step.termination {
+                    Some(termination) => {
+                        trace.output = termination.clone().into_output();
+                        return (trace, Ok(termination));
+                    }
+                    None => trace.steps.push(TraceStep { index: step.index, writes: step.writes, flags: step.flags, event: step.event }),
+                },
+                Err(error) => return (trace, Err(error)),
+            }
+        }
+    }
+
+    // Run the interpreter along the trace, stopping at the first difference
+    pub fn replay(&self, interpreter: &mut Interpreter) -> Result<(), Divergence> {
+        for (number, expected) in self.steps.iter().enumerate() {
+            let step = interpreter.step().map_err(Divergence::Error)?;
+            if step.termination.is_some() {
+                return Err(Divergence::Length { expected: self.steps.len(), actual: number });
+            }
+            let actual = TraceStep { index: step.index, writes: step.writes, flags: step.flags, event: step.event };
+            if !actual.same_as(expected) {
+                return Err(Divergence::Step { step: number, expected: expected.clone(), actual });
+            }
+        }
+
+        let step = interpreter.step().map_err(Divergence::Error)?;
+        let Some(termination) = step.termination else {
+            return Err(Divergence::Length { expected: self.steps.len(), actual: self.steps.len() + 1 });
+        };
+        let output = termination.into_output();
+        if output != self.output {
+            return Err(Divergence::Output { expected: self.output.clone(), actual: output });
+        }
+        Ok(())
+    }
+
+    // Compare the values and flags written and the output of two runs, which may be of different
+    // programs. Steps changing nothing are skipped, so a rewrite may jump differently while the
+    // values agree
+    pub fn diff(&self, other: &Trace) -> Result<(), Divergence> {
+        let changes = |trace: &Trace| trace.steps.iter().filter(|step| step.changes()).cloned().collect::<Vec<_>>();
+        let (expected, actual) = (changes(self), changes(other));
+        for number in 0..expected.len().max(actual.len()) {
+            let (expected, actual) = (expected.get(number), actual.get(number));
+            if expected.map(|step| (&step.writes, step.flags)) != actual.map(|step| (&step.writes, step.flags)) {
+                return Err(Divergence::Write { number, expected: expected.cloned(), actual: actual.cloned() });
+            }
+        }
+        if self.output != other.output {
+            return Err(Divergence::Output { expected: self.output.clone(), actual: other.output.clone() });
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let steps: Vec<Value> = self.steps.iter()
+            .map(|step| json!({ "index": step.index, "writes": step.writes, "flags": step.flags, "event": step.event }))
+            .collect();
+        serde_json::to_string_pretty(&json!({ "steps": steps, "output": self.output })).unwrap()
+    }
+
+    // None when `json` isn't a trace written by `to_json`
+    pub fn from_json(json: &str) -> Option<Trace> {
+        let value: Value = serde_json::from_str(json).ok()?;
+        let steps = value.get("steps")?.as_array()?.iter()
+            .map(|step| {
+                let index = step.get("index")?.as_u64()? as usize;
+                let writes = step.get("writes")?.as_array()?.iter()
+                    .map(|write| Some((write.get(0)?.as_str()?.to_string(), write.get(1)?.as_i64()?)))
+                    .collect::<Option<Vec<_>>>()?;
+                // Both are missing in traces recorded before flags and events were
+                let flags = match step.get("flags") {
+                    None | Some(Value::Null) => None,
+                    Some(flags) => Some(u8::try_from(flags.as_u64()?).ok()?),
+                };
+                let event = match step.get("event") {
+                    Some(event) => event.as_u64()?,
+                    None => 0,
+                };
+                Some(TraceStep { index, writes, flags, event })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let output = match value.get("output")? {
+            Value::Null => None,
+            output => Some(output.as_str()?.to_string()),
+        };
+        Some(Trace { steps, output })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_replay() {
+        let program = "\nmov   a, 5\nmov   b, a\nmov   c, a\ncall  proc_fact\ncall  print\nend\n\nproc_fact:\n    dec   b\n    mul   c, b\n    cmp   b, 1\n    jne   proc_fact\n    ret\n\nprint:\n    msg   a, '! = ', c ; output text\n    ret\n";
+        let mut recorded = Interpreter::new(program);
+        let (trace, result) = Trace::record(&mut recorded);
+        assert_eq!(Ok(Termination::End(String::from("5! = 120"))), result);
+        assert_eq!(Some(String::from("5! = 120")), trace.output);
+        assert_eq!(TraceStep { index: 1, writes: vec![(String::from("a"), 5)], flags: None, event: 1 }, trace.steps[1]);
+        // `cmp b, 1` with b = 4 sets only PF, with b = 1 it sets ZF and PF
+        assert_eq!(Some(0b10000), trace.steps[7].flags);
+        assert_eq!(Some(0b10001), trace.steps.iter().rev().find_map(|step| step.flags));
+
+        let trace = Trace::from_json(&trace.to_json()).unwrap();
+        let mut replayed = Interpreter::new(program);
+        assert_eq!(Ok(()), trace.replay(&mut replayed));
+        assert_eq!(recorded.to_string(), replayed.to_string());
+
+        let changed_program = program.replacen("mov   a, 5", "mov   a, 4", 1);
+        let mut changed = Interpreter::new(&changed_program);
+        assert_eq!(
+            Err(Divergence::Step {
+                step: 1,
+                expected: TraceStep { index: 1, writes: vec![(String::from("a"), 5)], flags: None, event: 1 },
+                actual: TraceStep { index: 1, writes: vec![(String::from("a"), 4)], flags: None, event: 1 },
+            }),
+            trace.replay(&mut changed)
+        );
+
+        assert_eq!(None, Trace::from_json("{\"steps\": [{\"index\": -1}]}"));
+
+        // Traces recorded before flags and events were still load
+        let old = "{\"steps\": [{\"index\": 0, \"writes\": [[\"a\", 1]]}, {\"index\": 1, \"writes\": []}], \"output\": \"1\"}";
+        let trace = Trace::from_json(old).unwrap();
+        assert_eq!(TraceStep { index: 0, writes: vec![(String::from("a"), 1)], flags: None, event: 0 }, trace.steps[0]);
+        assert_eq!(Ok(()), trace.replay(&mut Interpreter::new("mov a, 1\nmsg a\nend\n")));
+    }
+}
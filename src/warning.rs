@@ -0,0 +1,32 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    LabelShadowsRegister { label: String, line: usize },
+    // A called function can run past its end, into the function `into` or off the end of the program
+    MissingReturn { function: String, into: Option<String>, line: usize },
+}
+
+impl Warning {
+    pub fn line(&self) -> usize {
+        match self {
+            Warning::LabelShadowsRegister { line, .. } | Warning::MissingReturn { line, .. } => *line,
+        }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::LabelShadowsRegister { label, .. } => {
+                write!(f, "label `{}` has the same name as a register", label)
+            }
+            Warning::MissingReturn { function, into: Some(into), .. } => {
+                write!(f, "function `{}` can run into `{}` without `ret`", function, into)
+            }
+            Warning::MissingReturn { function, into: None, .. } => {
+                write!(f, "function `{}` can run off the end of the program without `ret`", function)
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use asmintr::Interpreter;
+
+// Counts the allocations and the bytes still allocated of each test thread, memory freed by another
+// thread counts against that one. It replaces the allocator of the whole binary, so it lives in its
+// own test instead of the unit tests of the library
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        LIVE.with(|live| live.set(live.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.with(|live| live.set(live.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+fn live() -> isize {
+    LIVE.with(|live| live.get())
+}
+
+#[test]
+fn check_run_allocations() {
+    let program = "mov a, 0\nmov b, 1000\nloop:\nadd a, b\nmov [b+8], a\nmov c, [b+8]\ndec b\ncmp b, 0\njne loop\nmsg 'sum = ', c\nend\n";
+    let mut interpreter = Interpreter::new(program);
+    let before = allocations();
+    let output = interpreter.run().unwrap().into_output();
+    // Registers and the terms of memory operands are keyed by symbols interned while parsing, so the
+    // loop itself only allocates the nodes of the 1008 bytes of memory it fills, not once per access
+    assert!(allocations() - before < 250);
+    assert_eq!(Some(String::from("sum = 500500")), output);
+    assert_eq!("Mov(\"a\", \"0\")", format!("{:?}", interpreter.program.instructions[0]));
+}
+
+#[test]
+fn check_dropped_programs() {
+    // Like `--watch` and `--stdin-loop`, every program gets names of its own that go away with it
+    let before = live();
+    for run in 0..100 {
+        let source = format!("mov r{}, {}\nmsg 'run ', r{}\nend\n", run, run, run);
+        let output = Interpreter::new(&source).run().unwrap().into_output();
+        assert_eq!(Some(format!("run {}", run)), output);
+    }
+    assert_eq!(before, live());
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// Write `source` to a file of its own and run the binary on it, giving stdout, stderr and the exit code
+fn run(name: &str, source: &str, quiet: bool) -> (String, String, i32) {
+    let path: PathBuf = std::env::temp_dir().join(format!("asmintr-cli-{}-{}.asm", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_asmintr"));
+    if quiet {
+        command.arg("--quiet");
+    }
+    let output = command.arg(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+        output.status.code().unwrap(),
+    )
+}
+
+#[test]
+fn check_returning_program() {
+    let source = "mov a, 5\nmsg 'a = ', a\nend\n";
+    assert_eq!((String::from("Some(\"a = 5\")\n"), String::new(), 0), run("returning", source, false));
+    assert_eq!((String::from("a = 5\n"), String::new(), 0), run("returning-quiet", source, true));
+}
+
+#[test]
+fn check_program_without_end() {
+    let source = "mov a, 1\n";
+    let note = "note: the program finished without reaching `end`, so it has no output\n";
+    assert_eq!((String::from("None\n"), String::from(note), 0), run("fell-through", source, false));
+    assert_eq!((String::new(), String::new(), 0), run("fell-through-quiet", source, true));
+}
+
+#[test]
+fn check_erroring_program() {
+    let source = "mov a, 1\ndiv a, 0\nend\n";
+    let error = "error: division by zero in `a`\n --> line 2\n  |\n2 | div a, 0\n  | ^^^^^^^^\n";
+    // Errors are reported the same way with --quiet
+    assert_eq!((String::new(), String::from(error), 1), run("erroring", source, false));
+    assert_eq!((String::new(), String::from(error), 1), run("erroring-quiet", source, true));
+}